@@ -4,4 +4,4 @@
 geckodriver &
 
 # Start the bot
-RUST_LOG=bestbot=debug bestbot --headless /config/config.toml
+RUST_LOG=bestbot=debug bestbot --headless /config/config.toml "$@"
@@ -1,25 +1,50 @@
 #![allow(non_snake_case)]
-use std::collections::VecDeque;
-use std::iter::FromIterator;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
 use fantoccini::{cookies::Cookie, Locator, elements::Element};
-use regex::Regex;
+use reqwest::cookie::{CookieStore, Jar};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use serde::Deserialize;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 use serde_json::Value as Json;
 use tokio::time::sleep;
 
-use crate::{common::BotClientState, discord::DiscordWebhook, twilio::TwilioClient};
-use crate::config::Config;
-use crate::gmail::GmailClient;
+use crate::challenge::{self, ChallengeDetected, ChallengeStatus};
+use crate::common::{self, BotClientState, PurchaseTracker};
+use crate::config::{Account, Config, Fulfillment, RateLimit, Sku, SkuMode};
+use crate::driver::WebdriverService;
+use crate::money::{self, Usd};
+use crate::notifier::Notifier;
+use crate::orders::OrderWatcher;
+use crate::output::{Event, Output};
+use crate::proxy::{self, ProxyPool};
+use crate::reload::{self, ConfigWatcher, SkuDiff};
+use crate::throttle::{self, RateLimiter, RetryReason};
+use crate::verification::{self, CodeProvider};
 
 static SIGN_IN_URL: &str = "https://www.bestbuy.com/identity/global/signin";
-static EMAIL_CODE_PAT: &str = r#"<span.+>(\d+)</span>"#;
+static CART_URL: &str = "https://www.bestbuy.com/cart";
+static HOME_URL: &str = "https://www.bestbuy.com/";
+
+/// A cart value that is kept as raw JSON if it doesn't match `T`, e.g., a
+/// fulfillment type added by Best Buy after this was written.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Lenient<T> {
+    Known(T),
+    Unknown(Json),
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 struct FulfillmentStore {
     storeId: String,
     storeName: String,
@@ -29,89 +54,146 @@ struct FulfillmentStore {
     storeZipCode: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "typeCode")]
 enum CartFulfillment {
     #[serde(rename = "SHIPPING")]
     Shipping {
-        zipcode: String,
-        minDate: u64,
-        daysTillFulfillment: u32,
-        maxDate: u64,
-        price: String, // "FREE" for free shipping
+        zipcode: Option<String>,
+        minDate: Option<u64>,
+        daysTillFulfillment: Option<u32>,
+        maxDate: Option<u64>,
+        #[serde(default, deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+        price: Option<Usd>, // "FREE" for free shipping
+        #[serde(default)]
         selected: bool,
+        #[serde(default)]
         isPreOrder: bool,
     },
     #[serde(rename = "IN_STORE_PICKUP")]
     InStorePickup {
-        daysTillPickup: String, // As a number
-        pickupDate: String, // "Tue, May 25"
+        daysTillPickup: Option<String>, // As a number
+        pickupDate: Option<String>, // "Tue, May 25"
+        #[serde(default)]
         pickUpToday: bool,
+        #[serde(default)]
         isCurbsideAvailable: bool,
+        #[serde(default)]
         selected: bool,
+        #[serde(default)]
         store: FulfillmentStore,
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 struct CartItemPrice {
-    linePrice: String,
-    regularPrice: String,
+    /// The price of the whole line, i.e., times the quantity
+    #[serde(deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+    linePrice: Option<Usd>,
+    #[serde(deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+    regularPrice: Option<Usd>,
 }
 
-#[derive(Debug, Deserialize)]
+/// The `typeCode` of a cart item. Codes other than `HARDGOOD`, e.g., for
+/// digital items or protection plans, are kept as is.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
 enum CartItemType {
-    #[serde(rename = "HARDGOOD")]
     HardGood,
+    Other(String),
 }
 
-#[derive(Debug, Deserialize)]
+impl From<String> for CartItemType {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "HARDGOOD" => CartItemType::HardGood,
+            _ => CartItemType::Other(code),
+        }
+    }
+}
+
+impl From<CartItemType> for String {
+    fn from(item_type: CartItemType) -> Self {
+        match item_type {
+            CartItemType::HardGood => "HARDGOOD".to_string(),
+            CartItemType::Other(code) => code,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 struct CartItem {
     skuId: String,
+    #[serde(default)]
     shortLabel: String,
-    imageUrl: String,
-    itemUrl: String,
-    fulfillments: Vec<CartFulfillment>,
-    typeCode: CartItemType,
+    imageUrl: Option<String>,
+    itemUrl: Option<String>,
+    #[serde(default)]
+    fulfillments: Vec<Lenient<CartFulfillment>>,
+    typeCode: Option<CartItemType>,
+    #[serde(default)]
     price: CartItemPrice,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct CartLineItem {
     id: String,
     quantity: u32,
-    quantityLimit: u32,
+    quantityLimit: Option<u32>,
     item: CartItem,
+    #[serde(default)]
     digital: bool,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
 struct CartSummary {
-    productTotal: String,
-    orderTotal: String,
+    #[serde(deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+    productTotal: Option<Usd>,
+    #[serde(deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+    orderTotal: Option<Usd>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Cart {
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Cart {
     id: String,
+    #[serde(default)]
     cartItemCount: String, // As a number
-    subtotalAmount: String, // As a number
+    #[serde(default, deserialize_with = "money::deserialize_lenient", serialize_with = "money::serialize_option")]
+    subtotalAmount: Option<Usd>,
+    #[serde(default)]
     lineItems: Vec<CartLineItem>,
-    fulfillments: Vec<CartFulfillment>,
+    #[serde(default)]
+    fulfillments: Vec<Lenient<CartFulfillment>>,
+    #[serde(default)]
     orderSummary: CartSummary,
+    #[serde(default)]
     paypalWalletEnabled: bool,
+    #[serde(default)]
     creditCardInProfile: bool,
+    /// The cart as returned by Best Buy, for debugging
+    #[serde(skip)]
+    raw: Json,
 }
 
 #[derive(Debug, Deserialize)]
-struct ItemPriceInfo {
-    regularPrice: f64,
-    currentPrice: f64,
-    customerPrice: f64,
+struct CartCount {
+    count: u32,
 }
 
-#[derive(Debug, Deserialize)]
-struct ItemInfo {
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ItemPriceInfo {
+    #[serde(with = "money")]
+    regularPrice: Usd,
+    #[serde(with = "money")]
+    currentPrice: Usd,
+    #[serde(with = "money")]
+    customerPrice: Usd,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ItemInfo {
     sku: String,
     name: String,
     url: String,
@@ -120,9 +202,63 @@ struct ItemInfo {
     description: String,
 }
 
+/// Availability and price of a single SKU.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StockStatus {
+    sku: String,
+    name: String,
+    #[serde(with = "money")]
+    price: Usd,
+    in_stock: bool,
+}
+
+impl fmt::Display for Cart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.lineItems.is_empty() {
+            return write!(f, "Cart is empty");
+        }
+
+        let unknown = || "unknown price".to_string();
+
+        for line_item in &self.lineItems {
+            writeln!(
+                f,
+                "{} x{}: \"{}\" for {}",
+                line_item.item.skuId,
+                line_item.quantity,
+                line_item.item.shortLabel,
+                line_item.item.price.linePrice.as_ref().map_or_else(unknown, Usd::to_string),
+            )?;
+        }
+
+        write!(f, "Total: {}", self.orderSummary.orderTotal.as_ref().map_or_else(unknown, Usd::to_string))
+    }
+}
+
+impl fmt::Display for ItemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "SKU: {}", self.sku)?;
+        writeln!(f, "Name: \"{}\"", self.name)?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Price: {} (regular: {})", self.price.currentPrice, self.price.regularPrice)?;
+        writeln!(f, "Image: {}", self.image_url)?;
+        write!(f, "Description: {}", self.description)
+    }
+}
+
+impl fmt::Display for StockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let availability = if self.in_stock { "In Stock" } else { "Not In Stock" };
+        write!(f, "{}: \"{}\", Price: {}, {}", self.sku, self.name, self.price, availability)
+    }
+}
+
 #[derive(Clone, Debug)]
-struct BestBuyApi {
+pub struct BestBuyApi {
     client: reqwest::Client,
+    base_url: String,
+    cookie_jar: Arc<Jar>,
+    limiter: Arc<RateLimiter>,
 }
 
 impl BestBuyApi {
@@ -138,23 +274,26 @@ impl BestBuyApi {
         }
     }
 
-    /// Build an API client from a list of cookies.
-    fn from_cookies(cookies: &[Cookie]) -> Result<Self> {
+    /// Build an API client from a list of cookies, optionally sending
+    /// requests through `proxy`.
+    pub fn from_cookies(cookies: &[Cookie], proxy: Option<&str>) -> Result<Self> {
+        Self::with_base_url(Self::BASE_URL, cookies, proxy)
+    }
+
+    /// Build an API client for the Best Buy API at `base_url`, e.g., a
+    /// local mock server in tests. Plain HTTP is only allowed for such URLs.
+    pub fn with_base_url(base_url: &str, cookies: &[Cookie], proxy: Option<&str>) -> Result<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let url: reqwest::Url = base_url.parse()?;
+
         // Build a cookie jar for use with the HTTP client
-        let cookie_jar = reqwest::cookie::Jar::default();
-        let url: reqwest::Url = Self::BASE_URL.parse().unwrap();
-        for cookie in cookies {
-            if Self::is_auth_cookie(cookie.name()) {
-                let encoded = cookie.encoded().to_string();
-                cookie_jar.add_cookie_str(&encoded, &url);
-            }
-        }
+        let cookie_jar = Arc::new(Jar::default());
 
         // Default headers for every request
         let default_headers: HeaderMap =
             [
-                ("Origin", Self::BASE_URL),
-                ("Referer", Self::BASE_URL),
+                ("Origin", base_url),
+                ("Referer", base_url),
                 ("Accept-Language", "en-US"),
             ]
             .iter()
@@ -166,25 +305,155 @@ impl BestBuyApi {
         // The BestBuy API only accepts HTTP/2 requests and relies on ALPN to handle
         // negotiating the protocol. As a result, we need to use the `rustls-tls`
         // backend for `reqwest` and explicitly enable it when building the client.
-        let client = reqwest::Client::builder()
+        let mut client = reqwest::Client::builder()
             .user_agent(Self::USER_AGENT)
             .default_headers(default_headers)
             .timeout(std::time::Duration::from_secs(10))
-            .cookie_provider(std::sync::Arc::new(cookie_jar))
-            .https_only(true)
-            .use_rustls_tls() // Needed for ALPN (HTTP -> HTTP2 upgrade)
-            .build()?;
+            .cookie_provider(cookie_jar.clone())
+            .https_only(url.scheme() == "https")
+            .use_rustls_tls(); // Needed for ALPN (HTTP -> HTTP2 upgrade)
 
-        Ok(Self {
-            client
-        })
+        if let Some(proxy) = proxy {
+            client = client.proxy(proxy::reqwest_proxy(proxy)?);
+        }
+
+        let client = client.build()?;
+
+        let api_client = Self {
+            client,
+            base_url: base_url.to_string(),
+            cookie_jar,
+            limiter: Arc::new(RateLimiter::new(&RateLimit::default())),
+        };
+        api_client.add_cookies(cookies);
+
+        Ok(api_client)
     }
 
-    /// Get pricing info for a given SKU.
-    async fn get_item_price(&self, sku: &str) -> Result<ItemPriceInfo> {
-        let endpoint = format!("{}/pricing/v1/price/item", Self::BASE_URL);
+    /// Share `limiter` with other API clients, e.g., the ones created for
+    /// the same account after switching proxies.
+    pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.limiter = limiter;
+    }
+
+    /// Add the auth cookies in `cookies` to the cookie jar, replacing any
+    /// with the same name.
+    pub fn add_cookies(&self, cookies: &[Cookie]) {
+        let url: reqwest::Url = self.base_url.parse().unwrap();
+        for cookie in cookies {
+            if Self::is_auth_cookie(cookie.name()) {
+                let encoded = cookie.encoded().to_string();
+                self.cookie_jar.add_cookie_str(&encoded, &url);
+            }
+        }
+    }
+
+    /// Returns the name and value of every auth cookie in the cookie jar.
+    pub fn auth_cookies(&self) -> Vec<(String, String)> {
+        let url: reqwest::Url = self.base_url.parse().unwrap();
+        let header = match self.cookie_jar.cookies(&url) {
+            Some(header) => header,
+            None => return Vec::new(),
+        };
 
-        let info: ItemPriceInfo = self.client
+        // The header is formatted as `name1=value1; name2=value2`
+        header
+            .to_str()
+            .unwrap_or_default()
+            .split("; ")
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(name, _)| Self::is_auth_cookie(name))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Send a request through the rate limiter and return the response
+    /// body. Idempotent requests, e.g., GETs, that hit a 429, a server error
+    /// or a timeout are retried with exponential backoff. Others, like
+    /// adding to the cart or placing an order, may have gone through, so
+    /// they are never sent twice.
+    ///
+    /// Fails with `ChallengeDetected` if Best Buy answered with a bot
+    /// challenge page, or with the HTTP error for any other error status.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<String> {
+        let idempotent = request
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .map(|request| request.method().is_idempotent())
+            .unwrap_or(false);
+        let mut attempt = 0;
+
+        let resp = loop {
+            let retry = request
+                .try_clone()
+                .ok_or_else(|| anyhow::format_err!("Request can't be retried"))?;
+
+            self.limiter.acquire().await;
+
+            let (reason, retry_after) = match retry.send().await {
+                Ok(resp) if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                    (RetryReason::TooManyRequests, Self::retry_after(&resp))
+                }
+                Ok(resp) if resp.status().is_server_error() => (RetryReason::ServerError, None),
+                Ok(resp) => break resp,
+                Err(e) if e.is_timeout() => (RetryReason::Timeout, None),
+                Err(e) => return Err(e.into()),
+            };
+
+            if !idempotent {
+                anyhow::bail!("Request failed ({}), not retrying since it may have gone through", reason);
+            }
+            if attempt >= self.limiter.max_retries() {
+                anyhow::bail!("Giving up after {} retries ({})", attempt, reason);
+            }
+
+            let delay = self.limiter.on_retry(reason, attempt, retry_after);
+            log::warn!("Request throttled ({}), retrying in {:?}", reason, delay);
+            sleep(delay).await;
+
+            attempt += 1;
+        };
+
+        // A 403 or other client error isn't a sign that the server can take
+        // more requests
+        if resp.status().is_success() || resp.status().is_redirection() {
+            self.limiter.on_success();
+        }
+
+        let status_error = resp.error_for_status_ref().err();
+        let url = resp.url().to_string();
+        let body = resp.text().await?;
+
+        if challenge::is_challenge(&body) {
+            return Err(ChallengeDetected { url }.into());
+        }
+
+        if let Some(e) = status_error {
+            return Err(e.into());
+        }
+
+        Ok(body)
+    }
+
+    /// Returns the delay in a `Retry-After` header given in seconds.
+    fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+        resp.headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs)
+    }
+
+    /// Send a request and deserialize the JSON response body.
+    async fn send_json<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<T> {
+        let body = self.send(request).await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    fn item_price_request(&self, sku: &str) -> reqwest::RequestBuilder {
+        let endpoint = format!("{}/pricing/v1/price/item", self.base_url);
+
+        self.client
             .get(endpoint)
             .header("X-CLIENT-ID", "lib-price-browser")
             .query(&[
@@ -195,20 +464,17 @@ impl BestBuyApi {
                 ("includeExpirationTimeStamp", "true"),
                 ("salesChannel", "LargeView"),
             ])
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+    }
+
+    /// Get pricing info for a given SKU.
+    async fn get_item_price(&self, sku: &str) -> Result<ItemPriceInfo> {
+        let info: ItemPriceInfo = self.send_json(self.item_price_request(sku)).await?;
 
         Ok(info)
     }
 
-    /// Get relevant info for a given item, including its price
-    async fn get_item_info(&self, sku: &str) -> Result<ItemInfo> {
-        let endpoint = format!("{}/api/tcfb/model.json", Self::BASE_URL);
-
-        let price = self.get_item_price(sku).await?;
+    fn item_model_request(&self, sku: &str) -> reqwest::RequestBuilder {
+        let endpoint = format!("{}/api/tcfb/model.json", self.base_url);
 
         // Query: item name, item URL, item image URL, and item description
         let paths = format!(r#"[
@@ -218,81 +484,103 @@ impl BestBuyApi {
             ["shop", "magellan", "v2", "product", "skus", {sku}, "descriptions", "long"]
         ]"#, sku=sku);
 
-        let json: Json = self.client
+        self.client
             .get(endpoint)
             .query(&[
                 ("method", "get"),
                 ("paths", &paths)
             ])
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+    }
+
+    /// Get relevant info for a given item, including its price
+    pub async fn get_item_info(&self, sku: &str) -> Result<ItemInfo> {
+        let price = self.get_item_price(sku).await?;
+        let json: Json = self.send_json(self.item_model_request(sku)).await?;
 
-        let name =
-            json["jsonGraph"]["shop"]["magellan"]["v2"]["product"]["skus"][sku]["names"]["short"]["value"].as_str().unwrap().to_string();
+        Self::parse_item_info(&self.base_url, sku, price, &json)
+    }
+
+    /// Build an item's info from its price and the `jsonGraph` returned by
+    /// `model.json`.
+    fn parse_item_info(base_url: &str, sku: &str, price: ItemPriceInfo, json: &Json) -> Result<ItemInfo> {
+        let value = |path: &[&str]| -> Result<&str> {
+            path.iter()
+                .fold(&json["jsonGraph"], |json, key| &json[key])
+                .as_str()
+                .ok_or_else(|| anyhow::format_err!("Item {} has no {} in model.json", sku, path.join(".")))
+        };
+
+        let name = value(&["shop", "magellan", "v2", "product", "skus", sku, "names", "short", "value"])?;
         let relative_url =
-            json["jsonGraph"]["shop"]["magellan"]["v1"]["sites"]["skuId"][sku]["sites"]["bbypres"]["relativePdpUrl"]["value"].as_str().unwrap();
-        let image_url =
-            json["jsonGraph"]["shop"]["magellan"]["v2"]["product"]["skus"][sku]["images"]["0"]["value"]["href"].as_str().unwrap().to_string();
-        let description =
-            json["jsonGraph"]["shop"]["magellan"]["v2"]["product"]["skus"][sku]["descriptions"]["long"]["value"].as_str().unwrap().to_string();
+            value(&["shop", "magellan", "v1", "sites", "skuId", sku, "sites", "bbypres", "relativePdpUrl", "value"])?;
+        let image_url = value(&["shop", "magellan", "v2", "product", "skus", sku, "images", "0", "value", "href"])?;
+        let description = value(&["shop", "magellan", "v2", "product", "skus", sku, "descriptions", "long", "value"])?;
 
-        let url = format!("{}{}", Self::BASE_URL, relative_url);
+        let url = format!("{}{}", base_url, relative_url);
 
         let item_info = ItemInfo {
             sku: sku.to_string(),
-            name,
+            name: name.to_string(),
             url,
             price,
-            image_url,
-            description,
+            image_url: image_url.to_string(),
+            description: description.to_string(),
         };
 
         Ok(item_info)
     }
 
-    /// Checks if a product is in stock by fetching the "add to cart"
-    /// button HTML component.
-    async fn is_in_stock(&self, sku: &str) -> Result<bool> {
+    fn add_to_cart_button_request(&self, sku: &str) -> reqwest::RequestBuilder {
         let endpoint = format!(
             "{}/site/canopy/component/fulfillment/add-to-cart-button/v1",
-            Self::BASE_URL
+            self.base_url
         );
 
-        let resp = self.client
+        self.client
             .get(endpoint)
             .query(&[("skuId", sku)])
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
+    }
+
+    /// Returns true if the "add to cart" button HTML lets us add the item.
+    fn parse_in_stock(button: &str) -> bool {
+        button.contains("Add to Cart")
+    }
 
-        let in_stock = resp.contains("Add to Cart");
+    /// Checks if a product is in stock by fetching the "add to cart"
+    /// button HTML component.
+    pub async fn is_in_stock(&self, sku: &str) -> Result<bool> {
+        let resp = self.send(self.add_to_cart_button_request(sku)).await?;
+
+        let in_stock = Self::parse_in_stock(&resp);
 
         log::debug!("{} is in stock: {}", sku, in_stock);
 
         Ok(in_stock)
     }
 
-    async fn get_cart_count(&self) -> Result<u32> {
-        let endpoint = format!("{}/basket/v1/basketCount", Self::BASE_URL);
+    /// Get the availability and price of a SKU.
+    pub async fn get_stock_status(&self, sku: &str) -> Result<StockStatus> {
+        let item_info = self.get_item_info(sku).await?;
+        let in_stock = self.is_in_stock(sku).await?;
 
-        #[derive(Deserialize)]
-        struct CartCount {
-            count: u32,
-        }
+        Ok(StockStatus {
+            sku: item_info.sku,
+            name: item_info.name,
+            price: item_info.price.currentPrice,
+            in_stock,
+        })
+    }
+
+    fn cart_count_request(&self) -> reqwest::RequestBuilder {
+        let endpoint = format!("{}/basket/v1/basketCount", self.base_url);
 
-        let resp: CartCount = self.client
+        self.client
             .get(endpoint)
             .header("X-CLIENT-ID", "browse")
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+    }
+
+    async fn get_cart_count(&self) -> Result<u32> {
+        let resp: CartCount = self.send_json(self.cart_count_request()).await?;
         let count = resp.count;
 
         log::debug!("Cart has {} items", count);
@@ -301,84 +589,228 @@ impl BestBuyApi {
     }
 
     /// Add a single item to the cart
-    #[allow(dead_code)]
-    async fn add_to_cart(&self, sku: &str) -> Result<()> {
-        let endpoint = format!("{}/cart/api/v1/addToCart", Self::BASE_URL);
+    ///
+    /// If a pickup store is given, the item is set up for in-store pickup.
+    async fn add_to_cart(&self, sku: &str, pickup_store_id: Option<&str>) -> Result<()> {
+        let endpoint = format!("{}/cart/api/v1/addToCart", self.base_url);
+        let mut item = serde_json::json!({"skuId": sku});
+
+        if let Some(store_id) = pickup_store_id {
+            item["storeId"] = serde_json::json!(store_id);
+            item["selectedFulfillment"] = serde_json::json!(
+                {
+                    "inStorePickup": {"pickupStoreId": store_id},
+                }
+            );
+        }
+
         let json = serde_json::json!(
             {
-                "items": [
-                    {"skuId": sku},
-                ]
+                "items": [item]
             }
         );
 
-        self.client
+        let request = self.client
             .post(&endpoint)
-            .json(&json)
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+            .json(&json);
+        self.send_json::<Json>(request).await?;
 
         Ok(())
     }
 
-    async fn get_cart(&self) -> Result<Cart> {
-        let endpoint = format!("{}/cart/json", Self::BASE_URL);
-        let resp: Json = self.client
-            .get(&endpoint)
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+    fn cart_request(&self) -> reqwest::RequestBuilder {
+        let endpoint = format!("{}/cart/json", self.base_url);
+        self.client.get(&endpoint)
+    }
+
+    /// Parse the `cart` object of a `/cart/json` response.
+    fn parse_cart(mut json: Json) -> Result<Cart> {
+        let cart_json = json
+            .get_mut("cart")
+            .map(Json::take)
+            .ok_or_else(|| anyhow::format_err!("Cart response has no cart"))?;
+
+        let mut cart: Cart = match Cart::deserialize(&cart_json) {
+            Ok(cart) => cart,
+            Err(e) => {
+                log::debug!("Unexpected cart: {}", cart_json);
+                return Err(anyhow::format_err!("Failed to parse the cart: {}", e));
+            }
+        };
+        cart.raw = cart_json;
+
+        // A known fulfillment type that no longer parses means that Best Buy
+        // changed its format, rather than added a new type
+        let fulfillments = cart.lineItems
+            .iter()
+            .flat_map(|line_item| &line_item.item.fulfillments)
+            .chain(&cart.fulfillments);
+        for fulfillment in fulfillments {
+            if let Lenient::Unknown(json) = fulfillment {
+                if matches!(json["typeCode"].as_str(), Some("SHIPPING") | Some("IN_STORE_PICKUP")) {
+                    if let Err(e) = CartFulfillment::deserialize(json) {
+                        log::debug!("Failed to parse a {} fulfillment, keeping it as is: {}", json["typeCode"], e);
+                    }
+                }
+            }
+        }
+
+        Ok(cart)
+    }
 
-        // TODO: Error handling
-        let cart_json = resp.as_object().unwrap().get("cart").unwrap().to_owned();
-        let cart: Cart = serde_json::from_value(cart_json)?;
+    pub async fn get_cart(&self) -> Result<Cart> {
+        let resp: Json = self.send_json(self.cart_request()).await?;
+        let cart = Self::parse_cart(resp)?;
 
-        log::trace!("{:?}", cart);
+        log::trace!("{}", cart.raw);
 
         Ok(cart)
     }
 
-    #[allow(dead_code)]
     async fn remove_from_cart(&self, item_id: &str) -> Result<()> {
-        let endpoint = format!("{}/cart/item/{}", Self::BASE_URL, item_id);
-        self.client
-            .delete(&endpoint)
-            .send()
-            .await?
-            .error_for_status()?;
+        let endpoint = format!("{}/cart/item/{}", self.base_url, item_id);
+        let request = self.client.delete(&endpoint);
+        self.send(request).await?;
         Ok(())
     }
 
     /// Modify an existing cart item
-    #[allow(dead_code)]
     async fn modify_cart_item(&self, item_id: &str, quantity: Option<u32>) -> Result<()> {
         if quantity.is_none() {
             return Ok(());
         }
 
-        let endpoint = format!("{}/cart/item/{}", Self::BASE_URL, item_id);
+        let endpoint = format!("{}/cart/item/{}", self.base_url, item_id);
         let mut json = serde_json::json!({});
 
         if let Some(quantity) = quantity {
             json["quantity"] = serde_json::json!(quantity);
         }
 
-        self.client
+        let request = self.client
             .put(&endpoint)
-            .json(&json)
-            .send()
-            .await?
-            .error_for_status()?;
+            .json(&json);
+        self.send(request).await?;
+
+        Ok(())
+    }
+
+    /// Add a SKU to the cart using its configured fulfillment and quantity.
+    async fn add_sku_to_cart(&self, sku: &Sku) -> Result<()> {
+        let pickup_store_id = match sku.fulfillment {
+            Some(Fulfillment::Pickup) => sku.store_id.as_deref(),
+            _ => None,
+        };
+
+        self.add_to_cart(&sku.sku, pickup_store_id).await?;
+
+        let cart = self.get_cart().await?;
+        let line_item = cart.lineItems
+            .iter()
+            .find(|line_item| line_item.item.skuId == sku.sku)
+            .ok_or_else(|| anyhow::format_err!("SKU {} was not added to the cart", sku.sku))?;
+
+        // The cart can have a different price than the product page, e.g.,
+        // for marketplace listings
+        if let (Some(max_price), Some(line_price)) = (&sku.max_price, &line_item.item.price.linePrice) {
+            if money::exceeds(line_price, max_price, line_item.quantity) {
+                self.remove_from_cart(&line_item.id).await?;
+                anyhow::bail!(
+                    "{} x{} is {} in the cart, above the max price of {} each",
+                    sku.sku, line_item.quantity, line_price, max_price,
+                );
+            }
+        }
+
+        // Best Buy limits how many of an item can be bought at once
+        let quantity = sku.quantity.unwrap_or(1);
+        let quantity = line_item.quantityLimit.map_or(quantity, |limit| quantity.min(limit));
+        if quantity != line_item.quantity {
+            self.modify_cart_item(&line_item.id, Some(quantity)).await?;
+        }
+
+        log::debug!("Added {} x{} to the cart", sku.sku, quantity);
+
+        Ok(())
+    }
+
+    /// Check out the current cart using the payment method saved in the
+    /// account. In a dry run, the order is never placed.
+    ///
+    /// The checkout endpoints are undocumented, so this is only used with
+    /// `bestbuy.api_checkout`.
+    ///
+    /// If every item in the cart has a max price in `skus`, the order total
+    /// must be known and at most the sum of the max prices.
+    async fn checkout(&self, dry_run: bool, skus: &[Sku]) -> Result<()> {
+        let cart = self.get_cart().await?;
+        if !cart.creditCardInProfile {
+            anyhow::bail!("No credit card saved in the BestBuy profile");
+        }
+
+        if let Some(max_total) = Self::max_order_total(&cart, skus) {
+            match &cart.orderSummary.orderTotal {
+                Some(total) if money::exceeds(total, &max_total, 1) => {
+                    anyhow::bail!("The order total of {} is above the max price of {}", total, max_total);
+                }
+                Some(_) => (),
+                None => anyhow::bail!("The order total is unknown, not checking out above the max price of {}", max_total),
+            }
+        }
+
+        match &cart.orderSummary.orderTotal {
+            Some(total) => log::info!("Checking out {} item(s) for {}", cart.lineItems.len(), total),
+            None => log::warn!("Checking out {} item(s), the order total is unknown", cart.lineItems.len()),
+        }
+
+        let endpoint = format!("{}/cart/checkout", self.base_url);
+        let request = self.client
+            .post(&endpoint)
+            .json(&serde_json::json!({}));
+        let resp: Json = self.send_json(request).await?;
+        let order_id = Self::parse_order_id(&resp)?;
+
+        log::debug!("Started checkout for order {}", order_id);
+
+        if dry_run {
+            log::info!("Dry run: not placing order {}", order_id);
+            return Ok(());
+        }
+
+        let endpoint = format!("{}/checkout/orders/{}/", self.base_url, order_id);
+        let request = self.client
+            .post(&endpoint)
+            .json(&serde_json::json!({}));
+        self.send(request).await?;
+
+        log::info!("Placed order {}", order_id);
 
         Ok(())
     }
 
-    async fn clear_cart(&self) -> Result<()> {
+    /// Returns the sum of the max price times the quantity of each line in
+    /// `cart`, or `None` if a line has no max price in `skus`.
+    fn max_order_total(cart: &Cart, skus: &[Sku]) -> Option<Usd> {
+        let mut total = Decimal::new(0, 2);
+        for line_item in &cart.lineItems {
+            let max_price = skus
+                .iter()
+                .find(|sku| sku.sku == line_item.item.skuId)
+                .and_then(|sku| sku.max_price.as_ref())?;
+            total += *max_price.amount() * Decimal::from(line_item.quantity);
+        }
+
+        Some(money::usd(total))
+    }
+
+    /// Get the order ID from the response to starting a checkout.
+    fn parse_order_id(json: &Json) -> Result<&str> {
+        json["updateData"]["order"]["id"]
+            .as_str()
+            .ok_or_else(|| anyhow::format_err!("Checkout response has no order ID"))
+    }
+
+    pub async fn clear_cart(&self) -> Result<()> {
         let cart = self.get_cart().await?;
 
         for line_item in &cart.lineItems {
@@ -391,28 +823,143 @@ impl BestBuyApi {
     }
 }
 
-#[derive(Clone)]
-struct WebdriverBot<'c, 'g> {
+/// Whether a configured selector matched on the sign-in page.
+#[derive(Debug, Serialize)]
+pub struct SelectorMatch {
+    pub field: &'static str,
+    pub selector: String,
+    pub matched: bool,
+}
+
+impl fmt::Display for SelectorMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = if self.matched { "matched" } else { "no match" };
+        write!(f, "{}: {} ({})", self.field, self.selector, status)
+    }
+}
+
+pub struct WebdriverBot<'c, 'g> {
     client: fantoccini::Client,
-    gmail_client: Option<&'g GmailClient>,
+    code_provider: Option<&'g dyn CodeProvider>,
     config: &'c Config,
+    account: &'c Account,
+    notifier: Option<Notifier>,
+    output: Output,
 }
 
 impl<'c, 'g> WebdriverBot<'c, 'g> {
-    const USERNAME_SEL: &'static str = r#"#fld-e"#;
-    const PASSWORD_SEL: &'static str = r#"#fld-p1"#;
-    const SUBMIT_SEL: &'static str = r#"div.cia-form__controls > button"#;
-    const VERIFICATION_CODE_SEL: &'static str = r#"input#verificationCode"#;
-    const VERIFICATION_CODE_FORM: &'static str = r#"form.cia-form"#;
-
-    fn new(client: fantoccini::Client,
-           gmail_client: Option<&'g GmailClient>,
-           config: &'c Config) -> Self {
+    const SELECTOR_TIMEOUT: Duration = Duration::from_secs(30);
+    const SELECTOR_POLL_INTERVAL: Duration = Duration::from_millis(500);
+    const DEFAULT_CHALLENGE_TIMEOUT: u64 = 1800;
+    const CHALLENGE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(client: fantoccini::Client,
+               code_provider: Option<&'g dyn CodeProvider>,
+               config: &'c Config,
+               account: &'c Account) -> Self {
         Self {
             client,
-            gmail_client,
+            code_provider,
             config,
+            account,
+            notifier: None,
+            output: Output::default(),
+        }
+    }
+
+    /// Send notifications through `notifier`, e.g., when a bot challenge
+    /// needs a human.
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = Some(notifier);
+    }
+
+    /// Emit `challenge` events through `output`.
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    fn emit_challenge(&self, url: &str, status: ChallengeStatus) {
+        self.output.emit(&Event::Challenge { account: self.account.name(), url, status });
+    }
+
+    async fn notify(&self, message: &str, png: Option<&[u8]>) {
+        let notifier = match &self.notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+
+        let result = match png {
+            Some(png) => notifier.send_with_image(message, png).await,
+            None => notifier.send(message).await,
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to send notification: {}", e);
+        }
+    }
+
+    async fn is_challenge_present(&mut self) -> Result<bool> {
+        let html = self.client.source().await?;
+        Ok(challenge::is_challenge(&html))
+    }
+
+    /// Check if the current page is a bot challenge or CAPTCHA. If it is,
+    /// save diagnostics and send a notification with a screenshot.
+    ///
+    /// With `challenge.handoff`, wait for a human to complete the challenge
+    /// in the browser and then resume. Otherwise, fail with
+    /// `ChallengeDetected`.
+    async fn check_challenge(&mut self) -> Result<()> {
+        if !self.is_challenge_present().await? {
+            return Ok(());
+        }
+
+        let url = self.client.current_url().await?.to_string();
+        log::warn!("Bot challenge detected at {}", url);
+
+        let dir = common::failure_dir(self.config, self.account, "challenge");
+        let error = ChallengeDetected { url: url.clone() }.into();
+        if let Err(e) = common::capture_failure(&mut self.client, &dir, &error).await {
+            log::warn!("Failed to save diagnostics to {}: {}", dir.display(), e);
+        }
+        let screenshot = std::fs::read(dir.join("screenshot.png")).ok();
+
+        let handoff = self.config.challenge.handoff;
+        let timeout = self.config.challenge.timeout.unwrap_or(Self::DEFAULT_CHALLENGE_TIMEOUT);
+        let timeout = Duration::from_secs(timeout);
+
+        let message = if handoff {
+            format!(
+                "Bot challenge for {} at {}. Complete it in the browser within {:?} and the bot will resume.",
+                self.account.name(), url, timeout,
+            )
+        } else {
+            format!("Bot challenge for {} at {}, stopping. See {}", self.account.name(), url, dir.display())
+        };
+        self.notify(&message, screenshot.as_deref()).await;
+
+        if !handoff {
+            self.emit_challenge(&url, ChallengeStatus::Stopped);
+            return Err(error);
+        }
+
+        log::info!("Waiting up to {:?} for the bot challenge to be completed in the browser", timeout);
+        self.emit_challenge(&url, ChallengeStatus::Waiting);
+
+        let deadline = Instant::now() + timeout;
+        while self.is_challenge_present().await? {
+            if Instant::now() >= deadline {
+                self.emit_challenge(&url, ChallengeStatus::TimedOut);
+                return Err(error.context(format!("The bot challenge was not completed within {:?}", timeout)));
+            }
+            sleep(Self::CHALLENGE_POLL_INTERVAL).await;
         }
+
+        log::info!("Bot challenge completed, resuming");
+        self.emit_challenge(&url, ChallengeStatus::Completed);
+        self.notify(&format!("Bot challenge completed for {}, resuming", self.account.name()), None).await;
+
+        Ok(())
     }
 
     async fn find_element(&mut self, selector: &str) -> Result<Element> {
@@ -429,42 +976,60 @@ impl<'c, 'g> WebdriverBot<'c, 'g> {
         Ok(matches.len() > 0)
     }
 
-    /// Get latest email code using Gmail API
-    async fn get_email_code(&self) -> Result<String> {
-        let username = &self.config.general.gmail_user.as_ref().expect("Gmail client not provided...");
-        let client = self.gmail_client.unwrap();
+    /// Returns the first selector that matches an element on the page.
+    async fn first_match<'s>(&mut self, selectors: &'s [String]) -> Result<Option<&'s str>> {
+        for selector in selectors {
+            if self.is_element_present(selector).await? {
+                return Ok(Some(selector));
+            }
+        }
+        Ok(None)
+    }
 
-        let messages = client
-            .list_messages(&username, "BestBuy", None)
-            .await?;
-        let latest_message = messages[0].id.as_ref().unwrap();
+    /// Wait until one of the selectors for `field` matches, and return the
+    /// matching element.
+    async fn wait_for_element(&mut self, field: &str, selectors: &[String]) -> Result<Element> {
+        let deadline = Instant::now() + Self::SELECTOR_TIMEOUT;
 
-        let body = client.get_message_body(&username, latest_message).await?;
-        let code_pat = Regex::new(EMAIL_CODE_PAT)?;
-        let code = code_pat.captures(&body).unwrap().get(1).unwrap().as_str().to_owned();
+        loop {
+            if let Some(selector) = self.first_match(selectors).await? {
+                log::debug!("Using selector {} for {}", selector, field);
+                return self.find_element(selector).await;
+            }
 
-        log::info!("Email code: {}", code);
+            if Instant::now() >= deadline {
+                anyhow::bail!("None of the `selectors.{}` matched: {:?}", field, selectors);
+            }
 
-        Ok(code)
+            sleep(Self::SELECTOR_POLL_INTERVAL).await;
+        }
     }
 
     /// Check if we have a verification code on the page. If we do, go through
-    /// the verification flow.
-    async fn verify_code(&mut self) -> Result<()> {
-        let verify_required = self.is_element_present(Self::VERIFICATION_CODE_SEL).await?;
-        if !verify_required {
-            return Ok(());
-        }
+    /// the verification flow using a code sent after `since`.
+    async fn verify_code(&mut self, since: SystemTime) -> Result<()> {
+        let selectors = &self.config.selectors;
+
+        let code_selector = match self.first_match(&selectors.verification_code).await? {
+            Some(selector) => selector,
+            None => return Ok(()),
+        };
+
+        log::info!("Verification required");
 
-        log::info!("Email verification required");
+        let code_provider = self.code_provider
+            .ok_or_else(|| anyhow::format_err!("Verification required, but no verification code source is configured"))?;
 
+        let form_selector = self.first_match(&selectors.verification_form)
+            .await?
+            .ok_or_else(|| anyhow::format_err!("None of the `selectors.verification_form` matched"))?;
         let form = self.client
-            .form(Locator::Css(Self::VERIFICATION_CODE_FORM))
+            .form(Locator::Css(form_selector))
             .await?;
-        let mut input = self.find_element(Self::VERIFICATION_CODE_SEL).await?;
+        let mut input = self.find_element(code_selector).await?;
 
-        // Get the verifcation code from Gmail
-        let code = self.get_email_code().await?;
+        // Get the verifcation code
+        let code = verification::wait_for_code(code_provider, since).await?;
         input.send_keys(&code).await?;
 
         // Submit the form
@@ -474,134 +1039,643 @@ impl<'c, 'g> WebdriverBot<'c, 'g> {
         Ok(())
     }
 
-    /// Sign in to BestBuy and return the list of cookies
-    async fn sign_in(&mut self) -> Result<Vec<Cookie<'_>>> {
-        let username = &self.config.bestbuy.as_ref().unwrap().username;
-        let password = &self.config.bestbuy.as_ref().unwrap().password;
-
-        log::debug!("Signing in...");
-
+    /// Load the sign-in page and check which of the configured selectors
+    /// match. The verification selectors only match once a code is requested.
+    pub async fn check_selectors(&mut self) -> Result<Vec<SelectorMatch>> {
         self.client.goto(SIGN_IN_URL).await?;
 
-        self.client.wait_for_find(Locator::Css(Self::USERNAME_SEL)).await?;
-        self.client.wait_for_find(Locator::Css(Self::PASSWORD_SEL)).await?;
-        self.client.wait_for_find(Locator::Css(Self::SUBMIT_SEL)).await?;
+        let mut matches = Vec::new();
+        for (field, selectors) in self.config.selectors.sign_in_fields().iter() {
+            for selector in selectors.iter() {
+                matches.push(SelectorMatch {
+                    field: *field,
+                    selector: selector.clone(),
+                    matched: self.is_element_present(selector).await?,
+                });
+            }
+        }
 
-        let mut username_input = self.client.find(
-            Locator::Css(Self::USERNAME_SEL)
-        ).await?;
-        let mut password_input = self.client.find(
-            Locator::Css(Self::PASSWORD_SEL)
-        ).await?;
-        let submit = self.client.find(
-            Locator::Css(Self::SUBMIT_SEL)
-        ).await?;
+        Ok(matches)
+    }
+
+    fn product_url(sku: &str) -> String {
+        format!("https://www.bestbuy.com/site/{sku}.p?skuId={sku}", sku=sku)
+    }
+
+    /// Checks if a product is in stock from the "add to cart" button on its
+    /// product page.
+    async fn is_in_stock(&mut self, sku: &str) -> Result<bool> {
+        self.client.goto(&Self::product_url(sku)).await?;
+        self.check_challenge().await?;
+
+        let mut button = self.wait_for_element("add_to_cart", &self.config.selectors.add_to_cart).await?;
+        let disabled = button.attr("disabled").await?.is_some();
+        let in_stock = !disabled && button.text().await?.contains("Add to Cart");
+
+        log::debug!("{} is in stock (browser): {}", sku, in_stock);
+
+        Ok(in_stock)
+    }
+
+    /// Add a SKU to the cart from its product page. The fulfillment and
+    /// quantity are left as the page's defaults.
+    async fn add_to_cart(&mut self, sku: &Sku) -> Result<()> {
+        if sku.fulfillment == Some(Fulfillment::Pickup) || sku.quantity.map_or(false, |quantity| quantity > 1) {
+            log::warn!("Browser checkout ignores the fulfillment and quantity of {}", sku.sku);
+        }
+
+        self.client.goto(&Self::product_url(&sku.sku)).await?;
+        self.check_challenge().await?;
+
+        let button = self.wait_for_element("add_to_cart", &self.config.selectors.add_to_cart).await?;
+        button.click().await?;
+
+        // Make sure the item made it to the cart
+        self.client.goto(CART_URL).await?;
+        self.check_challenge().await?;
+        let cart_item = format!(r#"a[href*="skuId={}"]"#, sku.sku);
+        if !self.is_element_present(&cart_item).await? {
+            anyhow::bail!("SKU {} was not added to the cart", sku.sku);
+        }
+
+        log::debug!("Added {} to the cart (browser)", sku.sku);
+
+        Ok(())
+    }
+
+    /// Check out the current cart from the cart page. In a dry run, the
+    /// order is never placed.
+    async fn checkout(&mut self, dry_run: bool) -> Result<()> {
+        self.client.goto(CART_URL).await?;
+        self.check_challenge().await?;
+
+        let checkout = self.wait_for_element("checkout", &self.config.selectors.checkout).await?;
+        checkout.click().await?;
+        self.client.wait_for_navigation(None).await?;
+        self.check_challenge().await?;
+
+        let place_order = self.wait_for_element("place_order", &self.config.selectors.place_order).await?;
+
+        if dry_run {
+            log::info!("Dry run: not placing the order (browser)");
+            return Ok(());
+        }
+
+        place_order.click().await?;
+        self.client.wait_for_navigation(None).await?;
+
+        log::info!("Placed order (browser)");
+
+        Ok(())
+    }
+
+    /// Reload the home page to keep the session and its anti-bot cookies
+    /// fresh.
+    async fn keep_alive(&mut self) -> Result<()> {
+        self.client.goto(HOME_URL).await?;
+        self.check_challenge().await?;
+        Ok(())
+    }
+
+    async fn cookies(&mut self) -> Result<Vec<Cookie<'static>>> {
+        let cookies = self.client.get_all_cookies().await?;
+        Ok(cookies)
+    }
+
+    /// Set cookies in the browser, keeping the domain and path of any
+    /// existing cookie with the same name.
+    async fn set_cookies(&mut self, cookies: &[(String, String)]) -> Result<()> {
+        let current = self.cookies().await?;
+
+        for (name, value) in cookies {
+            let existing = current.iter().find(|cookie| cookie.name() == name);
+            if existing.map_or(false, |cookie| cookie.value() == value) {
+                continue;
+            }
+
+            let domain = existing.and_then(|cookie| cookie.domain()).unwrap_or(".bestbuy.com");
+            let path = existing.and_then(|cookie| cookie.path()).unwrap_or("/");
+
+            let cookie = Cookie::build(name.clone(), value.clone())
+                .domain(domain.to_string())
+                .path(path.to_string())
+                .secure(true)
+                .finish();
+            self.client.add_cookie(cookie).await?;
+        }
+
+        Ok(())
+    }
+
+    /// End the WebDriver session.
+    pub async fn close(mut self) -> Result<()> {
+        self.client.close().await?;
+        Ok(())
+    }
+
+    /// Save diagnostics for a failed step, and add their location to the
+    /// error.
+    async fn capture_failure(&mut self, step: &str, error: anyhow::Error) -> anyhow::Error {
+        let dir = common::failure_dir(self.config, self.account, step);
+
+        match common::capture_failure(&mut self.client, &dir, &error).await {
+            Ok(()) => {
+                log::error!("Step {} failed, saved diagnostics to {}", step, dir.display());
+                error.context(format!("Step {} failed, see {}", step, dir.display()))
+            }
+            Err(e) => {
+                log::warn!("Failed to save diagnostics to {}: {}", dir.display(), e);
+                error
+            }
+        }
+    }
+
+    /// Sign in to BestBuy and return the list of cookies
+    pub async fn sign_in(&mut self) -> Result<Vec<Cookie<'static>>> {
+        match self.try_sign_in().await {
+            Ok(cookies) => Ok(cookies),
+            Err(e) => Err(self.capture_failure("sign-in", e).await),
+        }
+    }
+
+    async fn try_sign_in(&mut self) -> Result<Vec<Cookie<'static>>> {
+        let username = &self.account.username;
+        let password = &self.account.password;
+
+        log::debug!("Signing in as {}...", self.account.name());
+
+        self.client.goto(SIGN_IN_URL).await?;
+        self.check_challenge().await?;
+
+        let selectors = &self.config.selectors;
+
+        let mut username_input = self.wait_for_element("username", &selectors.username).await?;
+        let mut password_input = self.wait_for_element("password", &selectors.password).await?;
+        let submit = self.wait_for_element("submit", &selectors.submit).await?;
 
         username_input.send_keys(username).await?;
         password_input.send_keys(password).await?;
 
         // Submit the login form and wait for the new page to load
+        let submitted_at = SystemTime::now();
         submit.click().await?;
         self.client.wait_for_navigation(None).await?;
+        self.check_challenge().await?;
 
         // Check if we need to verify
-        self.verify_code().await?;
-
-        log::info!("Signed in successfully");
+        self.verify_code(submitted_at).await?;
+        self.check_challenge().await?;
 
         // Get the authentication cookies and return them
         let cookies = self.client.get_all_cookies().await?;
 
+        // Without them, something was rejected and the sign-in form is still shown
+        if !cookies.iter().any(|cookie| matches!(cookie.name(), "ut" | "at")) {
+            if self.first_match(&selectors.verification_code).await?.is_some() {
+                anyhow::bail!("The verification code was rejected");
+            }
+            anyhow::bail!("Sign-in was rejected, check the username and password of {}", self.account.name());
+        }
+
+        log::info!("Signed in successfully");
+
         Ok(cookies)
     }
 }
 
 /// A single instance of a BestBuy bot.
 ///
-/// Each bot checks the given list of products on every tick and adds
-/// all available to the cart before checking out.
-pub struct BestBuyBot<'c, 'g, 't> {
-    skus: VecDeque<String>,
-    gmail_client: Option<&'g GmailClient>,
+/// Each bot signs in to one account, checks the given list of products on
+/// every tick and adds all available to the cart before checking out.
+pub struct BestBuyBot<'c, 'g> {
+    skus: VecDeque<Sku>,
+    configured_skus: Vec<Sku>,
+    next_check: HashMap<String, Instant>,
+    interval: Duration,
+    code_provider: Option<&'g dyn CodeProvider>,
+    webdriver: WebdriverService,
+    browser: Option<WebdriverBot<'c, 'g>>,
+    use_browser: bool,
+    next_keep_alive: Instant,
+    /// The driver exited and the browser session still has to be restarted
+    restart_browser: bool,
+    proxies: ProxyPool,
+    proxy: Option<String>,
+    next_rotation: Option<Instant>,
+    limiter: Arc<RateLimiter>,
+    next_throttle_report: Instant,
     api_client: Option<BestBuyApi>,
     config: &'c Config,
-    twilio_client: Option<&'t TwilioClient>,
-    discord_webhook: Option<&'t DiscordWebhook>,
+    account: &'c Account,
+    config_watcher: Option<ConfigWatcher>,
+    reloaded_config: Option<Config>,
+    notifier: Notifier,
+    order_watcher: Option<OrderWatcher>,
+    /// `Purchased` notifications waiting for the order confirmation, by SKU
+    unconfirmed: HashMap<String, String>,
+    purchases: PurchaseTracker,
+    output: Output,
     state: BotClientState,
 }
 
-impl<'c, 'g, 't> BestBuyBot<'c, 'g, 't> {
+impl<'c, 'g> BestBuyBot<'c, 'g> {
+    const DEFAULT_INTERVAL: u64 = 20;
+    const DEFAULT_KEEP_ALIVE: u64 = 300;
+    const THROTTLE_REPORT_INTERVAL: Duration = Duration::from_secs(600);
+
     pub fn new(config: &'c Config,
-               gmail_client: Option<&'g GmailClient>,
-               twilio_client: Option<&'t TwilioClient>,
-               discord_webhook: Option<&'t DiscordWebhook>) -> Self {
-        let bestbuy = config.bestbuy.as_ref().expect("BestBuy config is not present!");
-        let skus = VecDeque::from_iter(bestbuy.skus.to_owned().into_iter());
+               account: &'c Account,
+               code_provider: Option<&'g dyn CodeProvider>,
+               notifier: Notifier,
+               purchases: PurchaseTracker,
+               proxies: ProxyPool) -> Self {
+        let mut skus = account.skus.to_owned();
+        skus.sort_by_key(|sku| Reverse(sku.priority));
 
-        assert!(skus.len() == 0, "No BestBuy SKUs specified");
+        assert!(!skus.is_empty(), "No BestBuy SKUs specified");
 
         Self {
             config,
-            skus,
-            gmail_client,
+            account,
+            configured_skus: skus.clone(),
+            skus: VecDeque::from(skus),
+            next_check: HashMap::new(),
+            interval: Duration::from_secs(config.general.interval.unwrap_or(Self::DEFAULT_INTERVAL)),
+            code_provider,
+            webdriver: WebdriverService::from_config(config),
+            browser: None,
+            use_browser: false,
+            next_keep_alive: Instant::now(),
+            restart_browser: false,
+            proxies,
+            proxy: None,
+            next_rotation: None,
+            limiter: Arc::new(RateLimiter::new(&config.rate_limit)),
+            next_throttle_report: Instant::now() + Self::THROTTLE_REPORT_INTERVAL,
             api_client: None,
-            twilio_client,
-            discord_webhook,
+            config_watcher: None,
+            reloaded_config: None,
+            notifier,
+            order_watcher: None,
+            unconfirmed: HashMap::new(),
+            purchases,
+            output: Output::default(),
             state: BotClientState::Started,
         }
     }
 
+    /// Reload SKUs, intervals and notifiers from the given config file
+    /// whenever it changes.
+    pub fn watch_config(&mut self, path: PathBuf) {
+        self.config_watcher = Some(ConfigWatcher::new(path));
+    }
+
+    /// Verify purchases against order confirmation emails, and alert on
+    /// cancelled orders.
+    pub fn watch_orders(&mut self, order_watcher: OrderWatcher) {
+        self.order_watcher = Some(order_watcher);
+    }
+
+    /// Print stock checks, state changes and notifications as JSON events.
+    pub fn set_output(&mut self, output: Output) {
+        self.output = output;
+    }
+
+    /// Apply changes made to the config file since the last check.
+    ///
+    /// Settings that are only read at startup, such as credentials, are left
+    /// as-is so that the current session is kept.
+    fn reload_config(&mut self) {
+        let config = match self.config_watcher.as_mut().and_then(|watcher| watcher.poll()) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                log::error!("Failed to reload config, keeping the current one: {}", e);
+                return;
+            }
+            None => return,
+        };
+
+        // Compare with the last reload, so each ignored change is only
+        // reported once
+        let current = self.reloaded_config.as_ref().unwrap_or(self.config);
+        for setting in reload::restart_required(current, &config, self.account) {
+            log::warn!("Config reload: ignoring change to {}, which requires a restart", setting);
+        }
+
+        let interval = Duration::from_secs(config.general.interval.unwrap_or(Self::DEFAULT_INTERVAL));
+        if interval != self.interval {
+            log::info!("Config reload: interval changed from {:?} to {:?}", self.interval, interval);
+            self.interval = interval;
+        }
+
+        let skus = reload::find_account(&config, self.account.name())
+            .map(|account| account.skus.clone())
+            .unwrap_or_default();
+        let diff = SkuDiff::new(&self.configured_skus, &skus);
+        if !diff.is_empty() {
+            diff.log();
+
+            self.skus.retain(|sku| !diff.removed.iter().any(|removed| removed.sku == sku.sku));
+
+            for sku in self.skus.iter_mut() {
+                if let Some(changed) = diff.changed.iter().find(|changed| changed.sku == sku.sku) {
+                    *sku = changed.clone();
+                    self.next_check.remove(&sku.sku);
+                }
+            }
+
+            self.skus.extend(diff.added.iter().cloned());
+            self.skus.make_contiguous().sort_by_key(|sku| Reverse(sku.priority));
+            self.configured_skus = skus;
+        }
+
+        if config.twilio != current.twilio || config.discord != current.discord {
+            match Notifier::from_config(&config) {
+                Ok(notifier) => {
+                    log::info!("Config reload: updated notifiers");
+                    self.notifier = notifier;
+                }
+                Err(e) => log::error!("Config reload: failed to update notifiers: {}", e),
+            }
+
+            if let Some(browser) = self.browser.as_mut() {
+                match Notifier::from_config(&config) {
+                    Ok(notifier) => browser.set_notifier(notifier),
+                    Err(e) => log::error!("Config reload: failed to update browser notifiers: {}", e),
+                }
+            }
+        }
+
+        self.reloaded_config = Some(config);
+    }
+
     fn api_client(&self) -> &BestBuyApi {
         self.api_client.as_ref().unwrap()
     }
 
     /// Try to send a notification when an item is purchased.
     async fn send_message(&self, message: &str) -> Result<()> {
-        if self.twilio_client.is_none() {
-            return Ok(());
+        self.output.emit(&Event::Notification { account: self.account.name(), message });
+        self.notifier.send(message).await
+    }
+
+    /// Send a notification for every order cancelled since the last check.
+    async fn check_cancellations(&mut self) -> Result<()> {
+        let cancelled = match self.order_watcher.as_mut() {
+            Some(order_watcher) => order_watcher.new_cancellations().await?,
+            None => return Ok(()),
+        };
+
+        for order_number in cancelled {
+            log::warn!("Order {} was cancelled", order_number);
+            self.send_message(&format!("Order cancelled: {}", order_number)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send the `Purchased` notification for every purchase confirmed by
+    /// email since the last check, with the order details, or for every
+    /// purchase that went a few minutes without a confirmation.
+    async fn check_confirmations(&mut self) -> Result<()> {
+        let confirmations = match self.order_watcher.as_mut() {
+            Some(order_watcher) => order_watcher.new_confirmations().await?,
+            None => return Ok(()),
+        };
+
+        for (sku, confirmation) in confirmations {
+            let purchased = self.unconfirmed
+                .remove(&sku)
+                .unwrap_or_else(|| format!("Purchased: {}", sku));
+            let message = match confirmation {
+                Some(confirmation) => format!("{}\n{}", purchased, confirmation),
+                None => format!("{} (no order confirmation email yet)", purchased),
+            };
+            self.send_message(&message).await?;
         }
 
-        if let Some(twilio_client) = &self.twilio_client {
-            let twilio_config = self.config.twilio.as_ref().unwrap();
+        Ok(())
+    }
 
-            twilio_client.send_message(
-                &twilio_config.from_number,
-                &twilio_config.to_number,
-                message
-            ).await?;
+    /// Returns true if a purchase is still waiting for its confirmation
+    /// email.
+    fn has_pending_confirmations(&self) -> bool {
+        matches!(&self.order_watcher, Some(order_watcher) if order_watcher.has_pending_confirmations())
+    }
 
-            log::info!("Sent notification SMS successfully");
+    /// Periodically reload a page in the browser, and sync the auth cookies
+    /// between the browser and the API client in both directions. If the
+    /// managed driver crashed, it is restarted and the bot signs in again.
+    async fn keep_browser_alive(&mut self, headless: bool) -> Result<()> {
+        let now = Instant::now();
+        if now < self.next_keep_alive {
+            return Ok(());
         }
 
-        if let Some(discord_webhook) = &self.discord_webhook {
-            discord_webhook.trigger(message).await?;
-            log::info!("Triggered Discord webhook successfully");
+        let interval = self.config.webdriver.keep_alive.unwrap_or(Self::DEFAULT_KEEP_ALIVE);
+        self.next_keep_alive = now + Duration::from_secs(interval);
+
+        // The browser session went down with the driver. The old session is
+        // kept until a new one is up, and a failed restart is retried on the
+        // next tick.
+        if self.restart_browser || self.webdriver.has_exited() {
+            log::warn!("WebDriver for {} exited, restarting it and signing in again", self.account.name());
+            self.restart_browser = true;
+            self.start_session(headless).await?;
+            self.restart_browser = false;
+            return Ok(());
         }
 
+        let (browser, api_client) = match (self.browser.as_mut(), self.api_client.as_ref()) {
+            (Some(browser), Some(api_client)) => (browser, api_client),
+            _ => return Ok(()),
+        };
+
+        // Cookies set by API responses first, so the page load refreshes them
+        browser.set_cookies(&api_client.auth_cookies()).await?;
+        browser.keep_alive().await?;
+
+        let cookies = browser.cookies().await?;
+        api_client.add_cookies(&cookies);
+
+        let cookie_path = common::cookie_store_path(self.config, self.account);
+        common::save_cookies(&cookie_path, &cookies)?;
+
+        log::debug!("Refreshed the browser session and synced cookies");
+
         Ok(())
     }
 
-    /// Run the client to completion for a given product.
-    async fn run(&mut self, sku: &str, _dry_run: bool) -> Result<BotClientState> {
-        let api_client = self.api_client.as_ref().unwrap();
+    /// Periodically log and emit how often the API client was throttled.
+    fn report_throttling(&mut self) {
+        let now = Instant::now();
+        if now < self.next_throttle_report {
+            return;
+        }
+        self.next_throttle_report = now + Self::THROTTLE_REPORT_INTERVAL;
+
+        let stats = self.limiter.stats();
+        log::info!("Throttling for {}: {}", self.account.name(), stats);
+        self.output.emit(&Event::Throttle { account: self.account.name(), stats: &stats });
+    }
+
+    /// Whether an API error means that Best Buy's bot protection rejected
+    /// the API client.
+    fn is_blocked(error: &anyhow::Error) -> bool {
+        let forbidden = error
+            .downcast_ref::<reqwest::Error>()
+            .and_then(|e| e.status())
+            .map_or(false, |status| status == reqwest::StatusCode::FORBIDDEN);
+
+        forbidden || error.is::<ChallengeDetected>()
+    }
+
+    /// Returns true if the account can sign in again through another proxy.
+    fn can_rotate_proxy(&self) -> bool {
+        match &self.proxy {
+            Some(proxy) => self.proxies.has_alternative(self.config.proxies(self.account), proxy),
+            None => false,
+        }
+    }
+
+    /// Switch to the browser for the rest of the run if `error` shows that
+    /// the API client is blocked. Switching proxies is preferred, since the
+    /// browser shares the blocked IP.
+    fn fall_back_to_browser(&mut self, error: &anyhow::Error) -> bool {
+        if self.browser.is_none() || !Self::is_blocked(error) || self.can_rotate_proxy() {
+            return false;
+        }
+
+        log::warn!("The API client was blocked ({}), switching to the browser", error);
+        self.use_browser = true;
+
+        true
+    }
+
+    /// The signed in browser session, used when the API client is blocked.
+    fn browser(&mut self) -> Result<&mut WebdriverBot<'c, 'g>> {
+        let account = self.account;
+        self.browser
+            .as_mut()
+            .ok_or_else(|| anyhow::format_err!("No browser session for {}", account.name()))
+    }
+
+    async fn is_in_stock(&mut self, sku: &Sku) -> Result<bool> {
+        if !self.use_browser {
+            match self.api_client().is_in_stock(&sku.sku).await {
+                Err(e) if self.fall_back_to_browser(&e) => (),
+                result => return result,
+            }
+        }
+
+        let browser = self.browser()?;
+        match browser.is_in_stock(&sku.sku).await {
+            Ok(in_stock) => Ok(in_stock),
+            Err(e) => Err(browser.capture_failure("stock-check", e).await),
+        }
+    }
+
+    async fn add_to_cart(&mut self, sku: &Sku) -> Result<()> {
+        if !self.use_browser {
+            match self.api_client().add_sku_to_cart(sku).await {
+                Err(e) if self.fall_back_to_browser(&e) => (),
+                result => return result,
+            }
+        }
+
+        let browser = self.browser()?;
+        match browser.add_to_cart(sku).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(browser.capture_failure("add-to-cart", e).await),
+        }
+    }
+
+    /// Empty the cart through the API. Once the API client is blocked the
+    /// cart is left as is, since the browser can't empty it.
+    async fn clear_cart(&mut self) -> Result<()> {
+        if self.use_browser {
+            log::warn!("The API client is blocked, so the cart of {} was not cleared", self.account.name());
+            return Ok(());
+        }
+
+        self.api_client().clear_cart().await
+    }
+
+    /// Check out in the browser, unless `bestbuy.api_checkout` opts in to
+    /// the cart API.
+    async fn checkout(&mut self, dry_run: bool) -> Result<()> {
+        if self.config.api_checkout() && !self.use_browser {
+            match self.api_client().checkout(dry_run, &self.configured_skus).await {
+                Err(e) if self.fall_back_to_browser(&e) => (),
+                result => return result,
+            }
+        }
 
+        let browser = self.browser()?;
+        match browser.checkout(dry_run).await {
+            Ok(()) => Ok(()),
+            Err(e) => Err(browser.capture_failure("checkout", e).await),
+        }
+    }
+
+    /// Run the client to completion for a given product.
+    ///
+    /// Each step uses the API client, or the signed in browser session once
+    /// the API client is blocked.
+    async fn run(&mut self, sku: &Sku, item_info: &ItemInfo, dry_run: bool) -> Result<BotClientState> {
         let mut state: BotClientState = self.state;
 
         loop {
             // Figure out what to do next based on current state
             match self.state {
                 BotClientState::SignedIn => {
-                    state = if api_client.is_in_stock(sku).await? {
+                    let in_stock = self.is_in_stock(sku).await?;
+
+                    let status = StockStatus {
+                        sku: sku.sku.clone(),
+                        name: item_info.name.clone(),
+                        price: item_info.price.currentPrice.clone(),
+                        in_stock,
+                    };
+                    self.output.emit(&Event::StockCheck { account: Some(self.account.name()), status: &status });
+
+                    state = if in_stock {
                         BotClientState::InStock
                     } else {
                         BotClientState::NotInStock
                     };
                 }
-                BotClientState::NotInStock | BotClientState::InStock => break,
+                BotClientState::InStock => {
+                    if sku.mode == SkuMode::NotifyOnly {
+                        break;
+                    }
+
+                    if !sku.allow_duplicates && !self.purchases.claim(&sku.sku, self.account.name()) {
+                        log::info!("{} is already being bought by another account", sku.sku);
+                        state = BotClientState::NotInStock;
+                        break;
+                    }
+
+                    self.add_to_cart(sku).await?;
+                    state = BotClientState::CartUpdated;
+                }
+                BotClientState::CartUpdated => {
+                    self.checkout(dry_run).await?;
+                    state = BotClientState::Purchased;
+
+                    // Nothing was bought, so don't leave the item behind
+                    if dry_run {
+                        if let Err(e) = self.clear_cart().await {
+                            log::warn!("Failed to clear the cart after a dry run: {}", e);
+                        }
+                    }
+                }
+                BotClientState::NotInStock | BotClientState::Purchased => break,
                 _ => unreachable!("Invalid state"),
             }
 
             self.state = state;
+            self.output.emit(&Event::StateChange { account: self.account.name(), sku: &sku.sku, state });
         }
 
         // Put the client back in the initial signed in state
@@ -610,59 +1684,250 @@ impl<'c, 'g, 't> BestBuyBot<'c, 'g, 't> {
         Ok(state)
     }
 
-    pub async fn start(&mut self, dry_run: bool, headless: bool) -> Result<()> {
-        let hostname = self.config.general.hostname.as_deref();
-        let interval = Duration::from_secs(self.config.general.interval.unwrap_or(20));
+    /// Sign in with a new browser session through the next proxy, if any,
+    /// and build the API client from its cookies.
+    async fn start_session(&mut self, headless: bool) -> Result<()> {
+        self.proxy = self.proxies.pick(self.config.proxies(self.account), self.proxy.as_deref());
+        if let Some(proxy) = &self.proxy {
+            log::info!("Using proxy {} for {}", proxy::redact(proxy), self.account.name());
+        }
 
         // Connect to the Webdriver client
-        let client = crate::common::new_webdriver_client(headless, hostname).await?;
+        let client = common::new_webdriver_client(
+            self.config,
+            &mut self.webdriver,
+            headless,
+            self.proxy.as_deref(),
+        ).await?;
 
         // Create a Webdriver bot for BestBuy
         let mut client = WebdriverBot::new(
             client,
-            self.gmail_client,
+            self.code_provider,
             self.config,
+            self.account,
         );
+        client.set_notifier(Notifier::from_config(self.config)?);
+        client.set_output(self.output);
 
         // Use the WebDriver bot to sign in to BestBuy
         // Then, feed the resulting cookies to the API client
         let cookies = client.sign_in().await?;
-        let api_client = BestBuyApi::from_cookies(&cookies)?;
+
+        // Save the cookies for one-off commands (e.g., `cart show`)
+        let cookie_path = common::cookie_store_path(self.config, self.account);
+        if let Err(e) = common::save_cookies(&cookie_path, &cookies) {
+            log::warn!("Failed to save cookies to {}: {}", cookie_path.display(), e);
+        }
+
+        let mut api_client = BestBuyApi::from_cookies(&cookies, self.proxy.as_deref())?;
+        api_client.set_rate_limiter(self.limiter.clone());
         self.api_client = Some(api_client);
         self.state = BotClientState::SignedIn;
 
+        // Keep the signed in browser session for when the API client is blocked
+        self.browser = Some(client);
+        self.use_browser = false;
+        self.next_keep_alive = Instant::now() + Duration::from_secs(
+            self.config.webdriver.keep_alive.unwrap_or(Self::DEFAULT_KEEP_ALIVE)
+        );
+        self.next_rotation = self.config.proxy.rotate.map(|rotate| Instant::now() + Duration::from_secs(rotate));
+
+        Ok(())
+    }
+
+    async fn close_browser(&mut self) {
+        if let Some(browser) = self.browser.take() {
+            if let Err(e) = browser.close().await {
+                log::warn!("Failed to close the browser: {}", e);
+            }
+        }
+    }
+
+    /// Sign in again through the next proxy.
+    async fn rotate_proxy(&mut self, headless: bool) -> Result<()> {
+        self.close_browser().await;
+        self.start_session(headless).await
+    }
+
+    /// If `error` shows that the current proxy is blocked, quarantine it,
+    /// and sign in again through another proxy if there is one. Returns
+    /// true if the session moved to another proxy.
+    async fn rotate_blocked_proxy(&mut self, error: &anyhow::Error, headless: bool) -> Result<bool> {
+        let proxy = match &self.proxy {
+            Some(proxy) if Self::is_blocked(error) => proxy.clone(),
+            _ => return Ok(false),
+        };
+
+        // Quarantining the only proxy would just delay its reuse
+        if !self.can_rotate_proxy() {
+            return Ok(false);
+        }
+        self.proxies.quarantine(&proxy);
+
+        log::warn!("Proxy {} was blocked ({}), switching to the next proxy", proxy::redact(&proxy), error);
+        self.rotate_proxy(headless).await?;
+
+        Ok(true)
+    }
+
+    /// Check every SKU in the queue that is due for a check, and buy the
+    /// ones that are in stock.
+    ///
+    /// If a product is out of stock, it is put back on the queue. Products
+    /// that are not due for a check yet are skipped.
+    async fn check_skus(&mut self, dry_run: bool, headless: bool) -> Result<()> {
+        let num_products = self.skus.len();
+
+        for _ in 0..num_products {
+            if let Some(sku) = self.skus.pop_front() {
+                let now = Instant::now();
+                if self.next_check.get(&sku.sku).map_or(false, |next| *next > now) {
+                    self.skus.push_back(sku);
+                    continue;
+                }
+
+                let interval = sku.interval.map_or(self.interval, Duration::from_secs);
+                let interval = throttle::jitter(interval, &self.config.rate_limit);
+                self.next_check.insert(sku.sku.clone(), now + interval);
+
+                // Get item info
+                let item_info = match self.api_client().get_item_info(&sku.sku).await {
+                    Ok(item_info) => item_info,
+                    Err(e) => {
+                        if self.rotate_blocked_proxy(&e, headless).await? {
+                            self.skus.push_back(sku);
+                            continue;
+                        }
+                        return Err(e);
+                    }
+                };
+                let (name, price) = (&item_info.name, &item_info.price.currentPrice);
+                log::info!("Name: \"{}\", Price: {}", name, price);
+
+                // Protect against scalper-priced marketplace listings
+                if sku.mode == SkuMode::Buy && sku.exceeds_max_price(price) {
+                    log::info!("{} is above the max price of {}, skipping", sku.sku, sku.max_price.as_ref().unwrap());
+                    self.skus.push_back(sku);
+                    continue;
+                }
+
+                let started_at = SystemTime::now();
+                let state = match self.run(&sku, &item_info, dry_run).await {
+                    Ok(state) => state,
+                    Err(e) => {
+                        let checking_out = matches!(self.state, BotClientState::CartUpdated);
+                        self.purchases.release(&sku.sku, self.account.name());
+                        self.state = BotClientState::SignedIn;
+                        if self.rotate_blocked_proxy(&e, headless).await? {
+                            self.skus.push_back(sku);
+                            continue;
+                        }
+
+                        // A failed checkout is retried on the next check
+                        // rather than stopping every other SKU
+                        if checking_out {
+                            log::error!("Failed to check out {}: {:#}", sku.sku, e);
+                            self.send_message(&format!("Checkout failed: {} ({})", name, e)).await?;
+                            if let Err(e) = self.clear_cart().await {
+                                log::warn!("Failed to clear the cart: {}", e);
+                            }
+                            self.skus.push_back(sku);
+                            continue;
+                        }
+
+                        return Err(e);
+                    }
+                };
+
+                match state {
+                    BotClientState::InStock => {
+                        let message = format!("In Stock: {} for {}", name, price);
+                        self.send_message(&message).await?;
+                    }
+                    BotClientState::Purchased => {
+                        // With Gmail, the notification waits for the order
+                        // confirmation, which is looked for on later
+                        // iterations so other SKUs are still checked
+                        match (self.order_watcher.as_mut(), dry_run) {
+                            (Some(order_watcher), false) => {
+                                order_watcher.expect_confirmation(started_at, &sku.sku)?;
+                                self.unconfirmed.insert(sku.sku.clone(), format!("Purchased: {} for {}", name, price));
+                            }
+                            (_, true) => self.send_message(&format!("Purchased (dry run): {} for {}", name, price)).await?,
+                            (None, false) => self.send_message(&format!("Purchased: {} for {}", name, price)).await?,
+                        }
+                    }
+                    _ => self.skus.push_back(sku),
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run until every SKU is handled. If the bot stops on an error, a
+    /// notification is sent before the error is returned.
+    pub async fn start(&mut self, dry_run: bool, headless: bool) -> Result<()> {
+        let result = self.try_start(dry_run, headless).await;
+
+        if let Err(e) = &result {
+            log::error!("Bot for {} stopped: {:#}", self.account.name(), e);
+            if let Err(e) = self.send_message(&format!("Bot stopped for {}: {}", self.account.name(), e)).await {
+                log::error!("Failed to send notification: {}", e);
+            }
+        }
+
+        self.close_browser().await;
+        self.webdriver.shutdown().await;
+
+        result
+    }
+
+    async fn try_start(&mut self, dry_run: bool, headless: bool) -> Result<()> {
+        self.start_session(headless).await?;
+
         // Clear the cart
         if self.api_client().get_cart_count().await? > 0 {
             self.api_client().clear_cart().await?;
         }
 
-        while self.skus.len() > 0 {
-            let num_products = self.skus.len();
+        while self.skus.len() > 0 || self.has_pending_confirmations() {
+            self.reload_config();
+
+            if let Err(e) = self.check_confirmations().await {
+                log::error!("Failed to check for order confirmations: {}", e);
+            }
 
-            // Check each of the products in the queue.
-            //
-            // If a product is out of stock, it is put back on the queue.
-            for _ in 0..num_products {
-                if let Some(sku) = self.skus.pop_front() {
-                    // Get item info
-                    let item_info = self.api_client().get_item_info(&sku).await?;
-                    let (name, price) = (&item_info.name, item_info.price.currentPrice);
-                    log::info!("Name: \"{}\", Price: ${}", name, price);
+            if let Err(e) = self.check_cancellations().await {
+                log::error!("Failed to check for cancelled orders: {}", e);
+            }
 
-                    match self.run(&sku, dry_run).await? {
-                        BotClientState::InStock => {
-                            let message = format!("In Stock: {} for ${}", name, price);
-                            self.send_message(&message).await?;
-                        }
-                        BotClientState::Purchased => {
-                            let message = format!("Purchased: {} for ${}", name, price);
-                            self.send_message(&message).await?;
-                        }
-                        _ => self.skus.push_back(sku),
-                    };
+            if let Err(e) = self.keep_browser_alive(headless).await {
+                log::error!("Failed to keep the browser session alive: {}", e);
+            }
+
+            self.report_throttling();
+
+            if self.next_rotation.map_or(false, |next| Instant::now() >= next) {
+                if self.can_rotate_proxy() {
+                    log::info!("Rotating {} to the next proxy", self.account.name());
+                    self.rotate_proxy(headless).await?;
+                } else {
+                    self.next_rotation = None;
                 }
             }
 
+            self.check_skus(dry_run, headless).await?;
+
+            // Sleep until the next product is due for a check
+            let now = Instant::now();
+            let interval = self.skus
+                .iter()
+                .filter_map(|sku| self.next_check.get(&sku.sku))
+                .min()
+                .map_or(self.interval, |next| next.saturating_duration_since(now));
+
             log::debug!("Sleeping for {:?}", interval);
 
             sleep(interval).await;
@@ -671,3 +1936,520 @@ impl<'c, 'g, 't> BestBuyBot<'c, 'g, 't> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::mock::{MockBestBuy, Scenario};
+    use crate::mock_webdriver::{MockWebdriver, SignIn};
+
+    static PS5: &str = "6426149";
+    static CABLE: &str = "6437121";
+
+    fn mock_api_client(server: &MockBestBuy) -> BestBuyApi {
+        BestBuyApi::with_base_url(&server.url(), &[], None).unwrap()
+    }
+
+    /// Read a recorded response from `fixtures/bestbuy`.
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/bestbuy").join(name);
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Can't read {}: {}", path.display(), e))
+    }
+
+    fn json_fixture(name: &str) -> Json {
+        serde_json::from_str(&fixture(name)).unwrap()
+    }
+
+    #[test]
+    fn test_parse_item_fixtures() {
+        let price: ItemPriceInfo = serde_json::from_value(json_fixture("price.json")).unwrap();
+        let expected = money::parse("$499.99").unwrap();
+        assert_eq!((&price.regularPrice, &price.currentPrice, &price.customerPrice), (&expected, &expected, &expected));
+
+        let item_info = BestBuyApi::parse_item_info(BestBuyApi::BASE_URL, PS5, price, &json_fixture("model.json")).unwrap();
+        assert_eq!(item_info.name, "Sony - PlayStation 5 Console");
+        assert_eq!(item_info.url, "https://www.bestbuy.com/site/sony-playstation-5-console/6426149.p?skuId=6426149");
+        assert!(item_info.image_url.ends_with("/6426149_sd.jpg"));
+        assert!(!item_info.description.is_empty());
+
+        // A missing field is an error rather than a panic
+        let price: ItemPriceInfo = serde_json::from_value(json_fixture("price.json")).unwrap();
+        let error = BestBuyApi::parse_item_info(BestBuyApi::BASE_URL, CABLE, price, &json_fixture("model.json")).unwrap_err();
+        assert!(error.to_string().contains("names.short"), "{}", error);
+
+        assert!(BestBuyApi::parse_in_stock(&fixture("add-to-cart-button-in-stock.html")));
+        assert!(!BestBuyApi::parse_in_stock(&fixture("add-to-cart-button-sold-out.html")));
+    }
+
+    #[test]
+    fn test_parse_cart_fixtures() {
+        let cart = BestBuyApi::parse_cart(json_fixture("cart.json")).unwrap();
+        assert_eq!(cart.cartItemCount, "2");
+        assert!(cart.creditCardInProfile);
+        assert_eq!(cart.orderSummary.orderTotal, money::parse("$524.98").ok());
+        assert_eq!(cart.subtotalAmount, money::parse("524.98").ok());
+
+        let skus: Vec<_> = cart.lineItems.iter().map(|line_item| line_item.item.skuId.as_str()).collect();
+        assert_eq!(skus, vec![PS5, CABLE]);
+
+        let ps5 = &cart.lineItems[0];
+        assert_eq!((ps5.quantity, ps5.quantityLimit), (1, Some(1)));
+        assert_eq!(ps5.item.typeCode, Some(CartItemType::HardGood));
+        assert_eq!(ps5.item.price.linePrice, money::parse("$499.99").ok());
+        assert!(matches!(
+            &ps5.item.fulfillments[0],
+            Lenient::Known(CartFulfillment::Shipping { price: Some(price), selected: true, .. }) if price.amount().is_zero()
+        ));
+        match &ps5.item.fulfillments[1] {
+            Lenient::Known(CartFulfillment::InStorePickup { store, selected, .. }) => {
+                assert_eq!(store.storeId, "0000");
+                assert!(!selected);
+            }
+            fulfillment => panic!("Expected in-store pickup, got {:?}", fulfillment),
+        }
+
+        assert_eq!(
+            cart.to_string(),
+            "6426149 x1: \"Sony - PlayStation 5 Console\" for $499.99\n\
+             6437121 x1: \"Insignia - 6' 8K Ultra High Speed HDMI 2.1 Cable\" for $24.99\n\
+             Total: $524.98"
+        );
+
+        // New item and fulfillment types are kept rather than rejected
+        let cart = BestBuyApi::parse_cart(json_fixture("cart-unknown-types.json")).unwrap();
+        let item_types: Vec<_> = cart.lineItems.iter().map(|line_item| line_item.item.typeCode.clone()).collect();
+        assert_eq!(item_types, vec![
+            Some(CartItemType::HardGood),
+            Some(CartItemType::Other("DIGITAL".to_string())),
+            Some(CartItemType::Other("WARRANTY".to_string())),
+        ]);
+        assert!(matches!(&cart.lineItems[0].item.fulfillments[1], Lenient::Unknown(json) if json["typeCode"] == "SHIP_TO_STORE"));
+        assert!(matches!(&cart.lineItems[1].item.fulfillments[0], Lenient::Unknown(json) if json["email"] == "user@example.com"));
+        assert_eq!(cart.lineItems[1].quantityLimit, None);
+        assert_eq!(cart.lineItems[2].item.price.linePrice, money::parse("$59.99").ok());
+        assert_eq!(cart.lineItems[2].item.price.regularPrice, None);
+        assert!(cart.lineItems[2].item.fulfillments.is_empty());
+        assert_eq!(cart.raw["lineItems"][2]["item"]["parentLineItemId"], "00000000-0000-0000-0000-000000000001");
+
+        // Unknown values are written back out as they came in
+        let json = serde_json::to_value(&cart).unwrap();
+        assert_eq!(json["lineItems"][1]["item"]["typeCode"], "DIGITAL");
+        assert_eq!(json["fulfillments"][1], cart.raw["fulfillments"][1]);
+
+        let cart = BestBuyApi::parse_cart(json_fixture("cart-empty.json")).unwrap();
+        assert!(cart.lineItems.is_empty());
+        assert_eq!(cart.to_string(), "Cart is empty");
+
+        assert!(BestBuyApi::parse_cart(serde_json::json!({"errors": []})).is_err());
+
+        let count: CartCount = serde_json::from_value(json_fixture("basket-count.json")).unwrap();
+        assert_eq!(count.count, 2);
+
+        let checkout = json_fixture("checkout.json");
+        assert_eq!(BestBuyApi::parse_order_id(&checkout).unwrap(), "BBY01-000000000000");
+    }
+
+    /// Replace account, contact and location details in a recorded
+    /// response.
+    fn sanitize(json: &mut Json) {
+        match json {
+            Json::Object(map) => {
+                // `state` is also used for order states outside addresses
+                let is_address = map.contains_key("addressLine1") || map.contains_key("postalCode");
+
+                for (key, value) in map.iter_mut() {
+                    let placeholder = match key.as_str() {
+                        "id" | "customerOrderId" => "00000000-0000-0000-0000-000000000000",
+                        "firstName" => "Jane",
+                        "lastName" => "Doe",
+                        "fullName" => "Jane Doe",
+                        "email" | "emailAddress" => "user@example.com",
+                        "phone" | "phoneNumber" | "mobilePhone" => "5555550100",
+                        "addressLine1" => "1 Example St",
+                        "addressLine2" => "",
+                        "city" => "Springfield",
+                        "state" if is_address => "MN",
+                        "zipcode" | "zipCode" | "postalCode" | "storeZipCode" => "00000",
+                        "storeId" => "0000",
+                        "storeName" => "Example Store",
+                        "storeAddress" => "1 Example St",
+                        "storeCity" => "Springfield",
+                        _ => {
+                            sanitize(value);
+                            continue;
+                        }
+                    };
+                    if value.is_string() {
+                        *value = Json::from(placeholder);
+                    }
+                }
+            }
+            Json::Array(values) => values.iter_mut().for_each(sanitize),
+            _ => (),
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut json = serde_json::json!({
+            "order": {"id": "BBY01-806612345678", "state": "INCOMPLETE"},
+            "shippingAddress": {
+                "firstName": "John",
+                "lastName": "Smith",
+                "addressLine1": "123 Main St",
+                "city": "Richfield",
+                "state": "MN",
+                "postalCode": "55423",
+                "phoneNumber": "6125551234",
+            },
+            "contact": {"email": "john.smith@gmail.com"},
+        });
+        sanitize(&mut json);
+
+        assert_eq!(json["order"], serde_json::json!({"id": "00000000-0000-0000-0000-000000000000", "state": "INCOMPLETE"}));
+        let address = &json["shippingAddress"];
+        assert_eq!((&address["firstName"], &address["lastName"]), (&Json::from("Jane"), &Json::from("Doe")));
+        assert_eq!((&address["addressLine1"], &address["postalCode"]), (&Json::from("1 Example St"), &Json::from("00000")));
+        assert_eq!(address["phoneNumber"], "5555550100");
+        assert_eq!(json["contact"]["email"], "user@example.com");
+    }
+
+    /// Refresh the fixtures from the live site:
+    ///
+    /// `BESTBUY_COOKIES=<account>-cookies.json cargo test record_fixtures -- --ignored`
+    ///
+    /// Without `BESTBUY_COOKIES`, saved by `bestbot login`, the cart
+    /// fixtures are left alone. Responses are only written if they still
+    /// parse.
+    #[tokio::test]
+    #[ignore]
+    async fn record_fixtures() {
+        let cookies = match std::env::var("BESTBUY_COOKIES") {
+            Ok(path) => common::load_cookies(Path::new(&path)).unwrap(),
+            Err(_) => Vec::new(),
+        };
+        let api_client = BestBuyApi::from_cookies(&cookies, None).unwrap();
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/bestbuy");
+
+        let parse_json = |body: String| {
+            let mut json: Json = serde_json::from_str(&body).unwrap();
+            sanitize(&mut json);
+            json
+        };
+        let write_json = |name: &str, json: &Json| {
+            std::fs::write(dir.join(name), serde_json::to_string_pretty(json).unwrap() + "\n").unwrap();
+        };
+
+        let price = parse_json(api_client.send(api_client.item_price_request(PS5)).await.unwrap());
+        let model = parse_json(api_client.send(api_client.item_model_request(PS5)).await.unwrap());
+        let price_info: ItemPriceInfo = serde_json::from_value(price.clone()).unwrap();
+        BestBuyApi::parse_item_info(BestBuyApi::BASE_URL, PS5, price_info, &model).unwrap();
+        write_json("price.json", &price);
+        write_json("model.json", &model);
+
+        let button = api_client.send(api_client.add_to_cart_button_request(PS5)).await.unwrap();
+        let name = if BestBuyApi::parse_in_stock(&button) {
+            "add-to-cart-button-in-stock.html"
+        } else {
+            "add-to-cart-button-sold-out.html"
+        };
+        std::fs::write(dir.join(name), button).unwrap();
+
+        if !cookies.is_empty() {
+            let count = parse_json(api_client.send(api_client.cart_count_request()).await.unwrap());
+            serde_json::from_value::<CartCount>(count.clone()).unwrap();
+            write_json("basket-count.json", &count);
+
+            let cart = parse_json(api_client.send(api_client.cart_request()).await.unwrap());
+            BestBuyApi::parse_cart(cart.clone()).unwrap();
+            write_json("cart.json", &cart);
+        }
+    }
+
+    /// Always has the same verification code.
+    struct StaticCode(&'static str);
+
+    #[async_trait::async_trait(?Send)]
+    impl CodeProvider for StaticCode {
+        async fn find_code(&self, _since: i64) -> Result<Option<String>> {
+            Ok(Some(self.0.to_string()))
+        }
+    }
+
+    fn sign_in_config(working_dir: &str) -> Config {
+        toml::from_str(&format!(r#"
+            [general]
+            working_dir = "{}"
+
+            [[bestbuy.accounts]]
+            username = "test@example.com"
+            password = "hunter2"
+        "#, working_dir)).unwrap()
+    }
+
+    /// Sign in through `webdriver` with the given verification code.
+    async fn sign_in(webdriver: &MockWebdriver, config: &Config, code: Option<&'static str>) -> Result<Vec<Cookie<'static>>> {
+        let account = &config.bestbuy.as_ref().unwrap().accounts[0];
+        let code_provider = code.map(StaticCode);
+
+        let client = fantoccini::ClientBuilder::native().connect(&webdriver.url()).await?;
+        let mut bot = WebdriverBot::new(client, code_provider.as_ref().map(|code| code as &dyn CodeProvider), config, account);
+
+        let result = bot.sign_in().await;
+        bot.close().await?;
+
+        result
+    }
+
+    fn cookie_names(cookies: &[Cookie]) -> Vec<String> {
+        cookies.iter().map(|cookie| cookie.name().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sign_in() {
+        let config = sign_in_config("");
+
+        let webdriver = MockWebdriver::start(SignIn::new("test@example.com", "hunter2"));
+        let cookies = sign_in(&webdriver, &config, None).await.unwrap();
+        assert_eq!(cookie_names(&cookies), vec!["ut", "at"]);
+        assert_eq!(webdriver.typed(), vec![
+            ("#fld-e".to_string(), "test@example.com".to_string()),
+            ("#fld-p1".to_string(), "hunter2".to_string()),
+        ]);
+        assert_eq!(webdriver.visited(), vec![SIGN_IN_URL, HOME_URL]);
+        assert!(webdriver.is_closed());
+
+        // The code is only entered when the verification form shows up
+        let webdriver = MockWebdriver::start(SignIn::new("test@example.com", "hunter2").verification_code("123456"));
+        let cookies = sign_in(&webdriver, &config, Some("123456")).await.unwrap();
+        assert_eq!(cookie_names(&cookies), vec!["ut", "at"]);
+        assert_eq!(webdriver.typed().last(), Some(&("input#verificationCode".to_string(), "123456".to_string())));
+        assert_eq!(webdriver.visited().last().map(String::as_str), Some(HOME_URL));
+    }
+
+    #[tokio::test]
+    async fn test_sign_in_errors() {
+        let working_dir = std::env::temp_dir().join(format!("bestbot-test-sign-in-{}", std::process::id()));
+        let config = sign_in_config(working_dir.to_str().unwrap());
+        let sign_in_error = |sign_in_scenario: SignIn, code: Option<&'static str>| {
+            let config = &config;
+            async move {
+                let webdriver = MockWebdriver::start(sign_in_scenario);
+                let error = sign_in(&webdriver, config, code).await.unwrap_err();
+                assert!(webdriver.is_closed());
+                error
+            }
+        };
+
+        let error = sign_in_error(SignIn::new("test@example.com", "hunter3"), None).await;
+        assert!(format!("{:#}", error).contains("Sign-in was rejected"), "{:#}", error);
+
+        let error = sign_in_error(SignIn::new("test@example.com", "hunter2").verification_code("123456"), None).await;
+        assert!(format!("{:#}", error).contains("no verification code source is configured"), "{:#}", error);
+
+        let error = sign_in_error(SignIn::new("test@example.com", "hunter2").verification_code("123456"), Some("654321")).await;
+        assert!(format!("{:#}", error).contains("verification code was rejected"), "{:#}", error);
+
+        let error = sign_in_error(SignIn::new("test@example.com", "hunter2").challenge(), None).await;
+        assert!(error.downcast_ref::<ChallengeDetected>().is_some(), "{:#}", error);
+
+        // Every failure saved diagnostics
+        let failures = std::fs::read_dir(working_dir.join("failures")).unwrap().count();
+        assert!(failures >= 4, "{} failures saved", failures);
+
+        std::fs::remove_dir_all(&working_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_api_client() {
+        let server = MockBestBuy::start(
+            Scenario::default()
+                .item(PS5, "Sony - PlayStation 5 Console", 499.99)
+                .in_stock_after(PS5, 2)
+        );
+        let api_client = mock_api_client(&server);
+
+        let item_info = api_client.get_item_info(PS5).await.unwrap();
+        assert_eq!(item_info.name, "Sony - PlayStation 5 Console");
+        assert_eq!(item_info.price.currentPrice.clone(), money::parse("$499.99").unwrap());
+
+        assert!(!api_client.is_in_stock(PS5).await.unwrap());
+        assert!(!api_client.is_in_stock(PS5).await.unwrap());
+        assert!(api_client.is_in_stock(PS5).await.unwrap());
+
+        // The quantity is capped by the cart's quantity limit
+        let mut sku = Sku::new(PS5.to_string());
+        sku.quantity = Some(3);
+        api_client.add_sku_to_cart(&sku).await.unwrap();
+        assert_eq!(server.cart(), vec![(PS5.to_string(), 2)]);
+        assert_eq!(api_client.get_cart_count().await.unwrap(), 2);
+
+        api_client.checkout(true, &[]).await.unwrap();
+        assert!(server.orders().is_empty());
+        api_client.checkout(false, &[]).await.unwrap();
+        assert_eq!(server.orders(), vec!["MOCK-ORDER-2"]);
+
+        api_client.add_sku_to_cart(&Sku::new(PS5.to_string())).await.unwrap();
+        api_client.clear_cart().await.unwrap();
+        assert!(server.cart().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_api_client_errors() {
+        // Throttling and server errors are retried
+        let server = MockBestBuy::start(Scenario::default().too_many_requests(1).server_errors(1));
+        let api_client = mock_api_client(&server);
+
+        assert_eq!(api_client.get_cart_count().await.unwrap(), 0);
+        let stats = api_client.limiter.stats();
+        assert_eq!((stats.too_many_requests, stats.server_errors, stats.retries), (1, 1, 2));
+        assert_eq!(server.requests().len(), 3);
+
+        // An expired session isn't
+        let server = MockBestBuy::start(Scenario::default().auth_expires_after(0));
+        let api_client = mock_api_client(&server);
+
+        let error = api_client.get_cart().await.unwrap_err();
+        let status = error.downcast_ref::<reqwest::Error>().and_then(|e| e.status());
+        assert_eq!(status, Some(reqwest::StatusCode::UNAUTHORIZED));
+        assert_eq!(server.requests().len(), 1);
+
+        // A timed out order may have been placed, so it isn't sent again
+        let server = MockBestBuy::start(
+            Scenario::default()
+                .item(PS5, "Sony - PlayStation 5 Console", 499.99)
+                .order_delay(Duration::from_secs(2)),
+        );
+        let api_client = BestBuyApi {
+            client: reqwest::Client::builder().timeout(Duration::from_millis(500)).build().unwrap(),
+            ..mock_api_client(&server)
+        };
+        api_client.add_sku_to_cart(&Sku::new(PS5.to_string())).await.unwrap();
+
+        let error = api_client.checkout(false, &[]).await.unwrap_err();
+        assert!(error.to_string().contains("not retrying"), "{:#}", error);
+        let orders = server.requests().into_iter().filter(|request| request.starts_with("POST /checkout/orders")).count();
+        assert_eq!(orders, 1);
+        assert_eq!(server.orders(), vec!["MOCK-ORDER-1"]);
+
+        // Taxes and fees can't take the order above the max price
+        let server = MockBestBuy::start(Scenario::default().item(PS5, "Sony - PlayStation 5 Console", 499.99).order_fees(40.0));
+        let api_client = mock_api_client(&server);
+        let mut sku = Sku::new(PS5.to_string());
+        sku.max_price = money::parse("499.99").ok();
+        api_client.add_sku_to_cart(&sku).await.unwrap();
+
+        let error = api_client.checkout(false, &[sku.clone()]).await.unwrap_err();
+        assert!(error.to_string().contains("above the max price"), "{:#}", error);
+        assert!(server.requests().iter().all(|request| !request.starts_with("POST /cart/checkout")));
+        sku.max_price = money::parse("539.99").ok();
+        api_client.checkout(false, &[sku]).await.unwrap();
+        assert_eq!(server.orders(), vec!["MOCK-ORDER-1"]);
+
+        let server = MockBestBuy::start(Scenario::default().item(PS5, "Sony - PlayStation 5 Console", 499.99).without_credit_card());
+        let api_client = mock_api_client(&server);
+        assert!(api_client.checkout(false, &[]).await.is_err());
+
+        // An item that costs more in the cart than its max price is removed
+        let server = MockBestBuy::start(Scenario::default().item(CABLE, "HDMI Cable", 19.99).cart_price(CABLE, 24.99));
+        let api_client = mock_api_client(&server);
+        let mut sku = Sku::new(CABLE.to_string());
+        sku.max_price = money::parse("19.99").ok();
+        let error = api_client.add_sku_to_cart(&sku).await.unwrap_err();
+        assert!(error.to_string().contains("$24.99 in the cart"), "{}", error);
+        assert!(server.cart().is_empty());
+    }
+
+    /// A bot that's signed in to `server`.
+    fn signed_in_bot<'c>(config: &'c Config, server: &MockBestBuy) -> BestBuyBot<'c, 'static> {
+        let account = &config.bestbuy.as_ref().unwrap().accounts[0];
+
+        let mut bot = BestBuyBot::new(
+            config,
+            account,
+            None,
+            Notifier::from_config(config).unwrap(),
+            PurchaseTracker::default(),
+            ProxyPool::from_config(config),
+        );
+        bot.api_client = Some(mock_api_client(server));
+        bot.state = BotClientState::SignedIn;
+
+        bot
+    }
+
+    #[tokio::test]
+    async fn test_bot_loop() {
+        let server = MockBestBuy::start(
+            Scenario::default()
+                .item(PS5, "Sony - PlayStation 5 Console", 499.99)
+                .in_stock_after(PS5, 2)
+                .item(CABLE, "HDMI Cable", 24.99)
+        );
+
+        let config: Config = toml::from_str(r#"
+            [general]
+            interval = 0
+
+            [rate_limit]
+            jitter = 0.0
+
+            [bestbuy]
+            api_checkout = true
+
+            [[bestbuy.accounts]]
+            username = "test@example.com"
+            skus = ["6426149", { sku = "6437121", max_price = 19.99 }]
+        "#).unwrap();
+        let mut bot = signed_in_bot(&config, &server);
+
+        for _ in 0..5 {
+            bot.check_skus(false, true).await.unwrap();
+        }
+
+        // The PS5 is bought once it comes in stock, and the cable is never
+        // checked since it's above its max price
+        assert_eq!(server.orders(), vec!["MOCK-ORDER-1"]);
+        assert_eq!(server.stock_checks(PS5), 3);
+        assert_eq!(server.stock_checks(CABLE), 0);
+        assert_eq!(bot.skus.iter().map(|sku| sku.sku.as_str()).collect::<Vec<_>>(), vec![CABLE]);
+    }
+
+    #[tokio::test]
+    async fn test_bot_checkout() {
+        let config: Config = toml::from_str(r#"
+            [general]
+            interval = 0
+
+            [bestbuy]
+            api_checkout = true
+
+            [[bestbuy.accounts]]
+            username = "test@example.com"
+            skus = ["6426149"]
+        "#).unwrap();
+
+        // A dry run doesn't leave the item in the cart
+        let server = MockBestBuy::start(Scenario::default().item(PS5, "Sony - PlayStation 5 Console", 499.99));
+        let mut bot = signed_in_bot(&config, &server);
+        bot.check_skus(true, true).await.unwrap();
+        assert!(server.orders().is_empty());
+        assert!(server.cart().is_empty());
+        assert!(bot.skus.is_empty());
+
+        // A failed checkout is retried later instead of stopping the bot
+        let server = MockBestBuy::start(
+            Scenario::default()
+                .item(PS5, "Sony - PlayStation 5 Console", 499.99)
+                .without_credit_card()
+        );
+        let mut bot = signed_in_bot(&config, &server);
+        bot.check_skus(false, true).await.unwrap();
+        assert!(server.orders().is_empty());
+        assert!(server.cart().is_empty());
+        assert_eq!(bot.skus.len(), 1);
+        assert!(bot.purchases.claim(PS5, "another account"));
+    }
+}
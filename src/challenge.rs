@@ -0,0 +1,82 @@
+use std::fmt;
+
+use regex::RegexSet;
+use serde::Serialize;
+
+/// Elements and titles of pages that block the bot until a human passes a
+/// challenge: Akamai Bot Manager interstitials, PerimeterX CAPTCHAs and
+/// Akamai "Access Denied" pages
+static CHALLENGE_PATS: &[&str] = &[
+    r#"(?i)<[a-z]+\s[^>]*\bid=["']sec-if-cpt-container["']"#,
+    r#"(?i)<script\s[^>]*\bsrc=["'][^"']*/_sec/cp_challenge/"#,
+    r#"(?i)<[a-z]+\s[^>]*\bid=["']px-captcha["']"#,
+    r#"(?i)<title>\s*access denied\s*</title>"#,
+];
+
+lazy_static::lazy_static! {
+    static ref CHALLENGE_SET: RegexSet = RegexSet::new(CHALLENGE_PATS).unwrap();
+}
+
+/// Returns true if the page is a bot challenge rather than the expected
+/// content.
+pub fn is_challenge(html: &str) -> bool {
+    CHALLENGE_SET.is_match(html)
+}
+
+/// What the bot does about a bot challenge in the browser.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeStatus {
+    /// Stopped without `challenge.handoff`
+    Stopped,
+    /// Waiting for a human to complete it
+    Waiting,
+    Completed,
+    TimedOut,
+}
+
+/// Best Buy answered with a bot challenge page.
+#[derive(Debug)]
+pub struct ChallengeDetected {
+    pub url: String,
+}
+
+impl fmt::Display for ChallengeDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bot challenge at {}", self.url)
+    }
+}
+
+impl std::error::Error for ChallengeDetected {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_challenge() {
+        let access_denied = r#"<HTML><HEAD>
+<TITLE>Access Denied</TITLE>
+</HEAD><BODY>
+<H1>Access Denied</H1>
+You don't have permission to access "http&#58;&#47;&#47;www&#46;bestbuy&#46;com&#47;cart" on this server.<P>
+Reference&#32;&#35;18&#46;2b1d3e17&#46;1621965790&#46;5a1b2c3
+</BODY>
+</HTML>"#;
+        assert!(is_challenge(access_denied));
+
+        let interstitial = r#"<div id="sec-if-cpt-container" class="sec-if-cpt-container">"#;
+        assert!(is_challenge(interstitial));
+
+        let captcha = r#"<div id='px-captcha'></div>"#;
+        assert!(is_challenge(captcha));
+
+        let add_to_cart = r#"<button class="btn btn-primary add-to-cart-button">Add to Cart</button>"#;
+        assert!(!is_challenge(add_to_cart));
+
+        // Mentions of a challenge in the page's content aren't one
+        let help = r#"<title>Help</title><p>Please verify you are a human if asked. Access Denied errors and
+            px-captcha or sec-if-cpt-container pages are explained below.</p>"#;
+        assert!(!is_challenge(help));
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use fantoccini::cookies::Cookie;
+use structopt::StructOpt;
+
+use crate::bestbuy::{BestBuyApi, BestBuyBot, WebdriverBot};
+use crate::common::{self, PurchaseTracker};
+use crate::config::{Account, Config};
+use crate::driver::WebdriverService;
+use crate::gmail;
+use crate::notifier::Notifier;
+use crate::orders::OrderWatcher;
+use crate::output::{Event, Output};
+use crate::proxy::ProxyPool;
+use crate::throttle::RateLimiter;
+use crate::verification;
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Run the bot for every account (default)
+    Run,
+    /// Print the availability and price of one or more SKUs
+    Check {
+        #[structopt(required = true)]
+        skus: Vec<String>,
+    },
+    /// Print all info for a SKU
+    Info {
+        sku: String,
+    },
+    /// Inspect or modify the cart of a signed in account
+    Cart(CartCommand),
+    /// Sign in and save the cookies for other commands
+    Login,
+    /// Send a test message to every notification channel
+    NotifyTest,
+    /// Sign in to Gmail and save the token used to read verification codes
+    GmailAuth {
+        /// Open the sign in URL on any machine and paste the code back, e.g.,
+        /// on a server without a browser
+        #[structopt(long)]
+        manual: bool,
+    },
+    /// Config file commands
+    Config(ConfigCommand),
+    /// Sign-in selector commands
+    Selectors(SelectorsCommand),
+}
+
+#[derive(StructOpt)]
+pub enum CartCommand {
+    /// Print the items in the cart
+    Show,
+    /// Remove every item from the cart
+    Clear,
+}
+
+#[derive(StructOpt)]
+pub enum ConfigCommand {
+    /// Check that the config file loads, including all secrets
+    Validate,
+}
+
+#[derive(StructOpt)]
+pub enum SelectorsCommand {
+    /// Load the sign-in page and report which selectors match
+    Check,
+}
+
+/// Find an account by name, or use the first one if no name is given.
+fn find_account<'a>(config: &'a Config, name: Option<&str>) -> Result<&'a Account> {
+    let accounts = config.bestbuy
+        .as_ref()
+        .map(|bestbuy| bestbuy.accounts.as_slice())
+        .unwrap_or_default();
+
+    let account = match name {
+        Some(name) => accounts.iter().find(|account| account.name() == name),
+        None => accounts.first(),
+    };
+
+    account.ok_or_else(|| anyhow::format_err!("BestBuy account not found: {}", name.unwrap_or("(any)")))
+}
+
+/// Build an API client with `cookies` that is rate limited and uses
+/// proxies like the bot's: the account's, or the global ones without an
+/// account.
+fn api_client(config: &Config, account: Option<&Account>, cookies: &[Cookie]) -> Result<BestBuyApi> {
+    let proxies = match account {
+        Some(account) => config.proxies(account),
+        None => &config.proxy.urls,
+    };
+    let proxy = ProxyPool::from_config(config).pick(proxies, None);
+
+    let mut api_client = BestBuyApi::from_cookies(cookies, proxy.as_deref())?;
+    api_client.set_rate_limiter(Arc::new(RateLimiter::new(&config.rate_limit)));
+
+    Ok(api_client)
+}
+
+/// Run a bot for every account until all SKUs are handled.
+pub async fn run(config: &Config, config_file: &Path, output: Output, dry_run: bool, headless: bool) -> Result<()> {
+    let bestbuy = config.bestbuy.as_ref().expect("BestBuy config is not present!");
+
+    if headless && config.challenge.handoff {
+        log::warn!("`challenge.handoff` is set with --headless, bot challenges can only be completed through a remote display such as VNC");
+    }
+
+    let mut code_providers = Vec::new();
+    for account in &bestbuy.accounts {
+        code_providers.push(verification::from_config(config, account).await?);
+    }
+
+    // Each account gets its own bot, and the bots share purchase claims
+    let purchases = PurchaseTracker::default();
+    let proxies = ProxyPool::from_config(config);
+    let mut bots = Vec::new();
+
+    for (account, code_provider) in bestbuy.accounts.iter().zip(&code_providers) {
+        let notifier = Notifier::from_config(config)?;
+
+        let mut bot = BestBuyBot::new(
+            config,
+            account,
+            code_provider.as_deref(),
+            notifier,
+            purchases.clone(),
+            proxies.clone(),
+        );
+
+        bot.watch_config(config_file.to_path_buf());
+        bot.set_output(output);
+
+        if let Some(order_watcher) = OrderWatcher::from_config(config, account).await? {
+            bot.watch_orders(order_watcher);
+        }
+
+        bots.push(bot);
+    }
+
+    // A failed account doesn't stop the others, each reports its own error
+    let runs = bots.iter_mut().map(|bot| bot.start(dry_run, headless));
+    let results = futures::future::join_all(runs).await;
+
+    let failed: Vec<&str> = bestbuy.accounts
+        .iter()
+        .zip(&results)
+        .filter(|(_, result)| result.is_err())
+        .map(|(account, _)| account.name())
+        .collect();
+    if !failed.is_empty() {
+        anyhow::bail!("The bot stopped with an error for {}", failed.join(", "));
+    }
+
+    Ok(())
+}
+
+pub async fn check(config: &Config, account: Option<&str>, skus: &[String], output: Output) -> Result<()> {
+    let account = account.map(|name| find_account(config, Some(name))).transpose()?;
+    let api_client = api_client(config, account, &[])?;
+
+    for sku in skus {
+        let status = api_client.get_stock_status(sku).await?;
+        output.print(&status, &Event::StockCheck { account: None, status: &status });
+    }
+
+    Ok(())
+}
+
+pub async fn info(config: &Config, account: Option<&str>, sku: &str, output: Output) -> Result<()> {
+    let account = account.map(|name| find_account(config, Some(name))).transpose()?;
+    let api_client = api_client(config, account, &[])?;
+    let item_info = api_client.get_item_info(sku).await?;
+
+    output.print(&item_info, &Event::ItemInfo(&item_info));
+
+    Ok(())
+}
+
+pub async fn cart(config: &Config, account: Option<&str>, command: &CartCommand, output: Output) -> Result<()> {
+    let account = find_account(config, account)?;
+    let cookies = common::load_cookies(&common::cookie_store_path(config, account))?;
+    let api_client = api_client(config, Some(account), &cookies)?;
+
+    match command {
+        CartCommand::Show => {
+            let cart = api_client.get_cart().await?;
+            output.print(&cart, &Event::Cart(&cart));
+        }
+        CartCommand::Clear => {
+            api_client.clear_cart().await?;
+            println!("Cleared the cart for {}", account.name());
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn login(config: &Config, account: Option<&str>, headless: bool, output: Output) -> Result<()> {
+    let account = find_account(config, account)?;
+    let code_provider = verification::from_config(config, account).await?;
+
+    let proxy = ProxyPool::from_config(config).pick(config.proxies(account), None);
+    let mut webdriver = WebdriverService::from_config(config);
+    let client = common::new_webdriver_client(config, &mut webdriver, headless, proxy.as_deref()).await?;
+    let mut client = WebdriverBot::new(client, code_provider.as_deref(), config, account);
+    client.set_notifier(Notifier::from_config(config)?);
+    client.set_output(output);
+
+    let cookies = client.sign_in().await?;
+    client.close().await?;
+    webdriver.shutdown().await;
+
+    let cookie_path = common::cookie_store_path(config, account);
+    common::save_cookies(&cookie_path, &cookies)?;
+
+    println!("Signed in as {}, saved cookies to {}", account.name(), cookie_path.display());
+
+    Ok(())
+}
+
+pub async fn check_selectors(config: &Config, account: Option<&str>, headless: bool, output: Output) -> Result<()> {
+    let account = find_account(config, account)?;
+
+    let proxy = ProxyPool::from_config(config).pick(config.proxies(account), None);
+    let mut webdriver = WebdriverService::from_config(config);
+    let client = common::new_webdriver_client(config, &mut webdriver, headless, proxy.as_deref()).await?;
+    let mut client = WebdriverBot::new(client, None, config, account);
+    client.set_output(output);
+
+    let matches = client.check_selectors().await;
+    client.close().await?;
+    webdriver.shutdown().await;
+
+    for selector_match in matches? {
+        output.print(&selector_match, &Event::SelectorCheck(&selector_match));
+    }
+
+    Ok(())
+}
+
+pub async fn notify_test(config: &Config) -> Result<()> {
+    let notifier = Notifier::from_config(config)?;
+    notifier.send("Test notification from bestbot").await?;
+
+    println!("Sent a test notification");
+
+    Ok(())
+}
+
+pub async fn gmail_auth(config: &Config, account: Option<&str>, manual: bool) -> Result<()> {
+    let account = find_account(config, account)?;
+    let username = config
+        .gmail_user(account)
+        .ok_or_else(|| anyhow::format_err!("Account {} has no `gmail_user`", account.name()))?;
+
+    let (app_secret_path, token_persist_path) = gmail::token_paths(config, username);
+    gmail::authorize(&app_secret_path, &token_persist_path, manual).await?;
+
+    println!("Signed in to Gmail as {}, saved the token to {}", username, token_persist_path.display());
+
+    Ok(())
+}
+
+pub fn validate_config(config_file: &Path) -> Result<()> {
+    match Config::load(config_file) {
+        Ok(config) => {
+            let accounts = config.bestbuy
+                .as_ref()
+                .map(|bestbuy| bestbuy.accounts.as_slice())
+                .unwrap_or_default();
+
+            println!("{} is valid", config_file.display());
+
+            for account in accounts {
+                println!("Account {}: {} SKU(s)", account.name(), account.skus.len());
+            }
+
+            Ok(())
+        }
+        Err(e) => Err(e.context(format!("{} is invalid", config_file.display()))),
+    }
+}
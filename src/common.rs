@@ -1,7 +1,20 @@
-use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use fantoccini::cookies::Cookie;
+use serde::Serialize;
+use serde_json::{json, Value as Json};
+
+use crate::config::{Account, Browser, Config, PageLoadStrategy, Webdriver};
+use crate::driver::WebdriverService;
+use crate::proxy;
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BotClientState {
     Started,
     SignedIn,
@@ -11,46 +24,286 @@ pub enum BotClientState {
     Purchased,
 }
 
-/// Creates a new Webdriver client
-pub async fn new_webdriver_client(headless: bool, hostname: Option<&str>) -> Result<fantoccini::Client> {
-    let hostname = hostname.unwrap_or("http://localhost:4444");
+/// Tracks which account is buying each SKU, so that bots running in
+/// parallel don't buy the same item more than once.
+#[derive(Clone, Default)]
+pub struct PurchaseTracker {
+    claims: Arc<Mutex<HashMap<String, String>>>,
+}
 
-    let mut client = fantoccini::ClientBuilder::native();
+impl PurchaseTracker {
+    /// Claim a SKU for an account. Returns false if another account already
+    /// claimed it.
+    pub fn claim(&self, sku: &str, account: &str) -> bool {
+        let mut claims = self.claims.lock().unwrap();
+        match claims.get(sku) {
+            Some(owner) if owner != account => false,
+            _ => {
+                claims.insert(sku.to_string(), account.to_string());
+                true
+            }
+        }
+    }
+
+    /// Release a claim, e.g., if the purchase failed.
+    pub fn release(&self, sku: &str, account: &str) {
+        let mut claims = self.claims.lock().unwrap();
+        if claims.get(sku).map_or(false, |owner| owner == account) {
+            claims.remove(sku);
+        }
+    }
+}
+
+/// Path of the file used to persist an account's cookies between runs.
+pub fn cookie_store_path(config: &Config, account: &Account) -> PathBuf {
+    let working_dir = config.general.working_dir.as_deref().unwrap_or("");
+    PathBuf::new().join(working_dir).join(format!("{}-cookies.json", account.name()))
+}
+
+/// Save cookies to disk as a JSON list of `Set-Cookie` strings.
+pub fn save_cookies(path: &Path, cookies: &[Cookie]) -> Result<()> {
+    let encoded: Vec<String> = cookies
+        .iter()
+        .map(|cookie| cookie.encoded().to_string())
+        .collect();
+
+    std::fs::write(path, serde_json::to_string_pretty(&encoded)?)?;
+
+    log::debug!("Saved {} cookies to {}", encoded.len(), path.display());
+
+    Ok(())
+}
+
+/// Load cookies saved by `save_cookies`.
+pub fn load_cookies(path: &Path) -> Result<Vec<Cookie<'static>>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("No saved cookies at {}, run `bestbot login` first", path.display()))?;
+    let encoded: Vec<String> = serde_json::from_str(&contents)?;
+
+    let cookies = encoded
+        .into_iter()
+        .map(|cookie| Cookie::parse_encoded(cookie).map_err(Into::into))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(cookies)
+}
+
+/// Directory for the diagnostics of a failed WebDriver step:
+/// `<working_dir>/failures/<timestamp>-<account>-<step>`.
+pub fn failure_dir(config: &Config, account: &Account, step: &str) -> PathBuf {
+    let working_dir = config.general.working_dir.as_deref().unwrap_or("");
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis());
+
+    PathBuf::new()
+        .join(working_dir)
+        .join("failures")
+        .join(format!("{}-{}-{}", timestamp, account.name(), step))
+}
+
+/// Save a screenshot, the page source, the current URL and the cookies of
+/// the browser to `dir`, along with the error that caused the failure.
+///
+/// Each item is saved on a best-effort basis, since the session may be in a
+/// bad state.
+pub async fn capture_failure(client: &mut fantoccini::Client, dir: &Path, error: &anyhow::Error) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+
+    std::fs::write(dir.join("error.txt"), format!("{:?}\n", error))?;
+
+    match client.current_url().await {
+        Ok(url) => std::fs::write(dir.join("url.txt"), format!("{}\n", url))?,
+        Err(e) => log::warn!("Failed to get the current URL: {}", e),
+    }
+
+    match client.screenshot().await {
+        Ok(png) => std::fs::write(dir.join("screenshot.png"), png)?,
+        Err(e) => log::warn!("Failed to take a screenshot: {}", e),
+    }
+
+    match client.source().await {
+        Ok(html) => std::fs::write(dir.join("page.html"), html)?,
+        Err(e) => log::warn!("Failed to get the page source: {}", e),
+    }
+
+    match client.get_all_cookies().await {
+        Ok(cookies) => save_cookies(&dir.join("cookies.json"), &cookies)?,
+        Err(e) => log::warn!("Failed to get cookies: {}", e),
+    }
+
+    Ok(())
+}
+
+/// Chrome arguments used in headless mode, e.g., inside Docker
+static CHROME_HEADLESS_ARGS: &[&str] = &[
+    "--no-sandbox",
+    "--headless",
+    "--start-maximized",
+    "--ignore-certificate-errors",
+    "--disable-extensions",
+    "--blink-settings=imagesEnabled=false",
+];
+
+/// Chrome arguments used in headless mode to skip proxy detection when no
+/// proxy is configured
+static CHROME_NO_PROXY_ARGS: &[&str] = &[
+    "--no-proxy-server",
+    "--proxy-server='direct://'",
+    "--proxy-bypass-list=*",
+];
 
+/// Window size used in headless mode if none is configured
+const HEADLESS_WINDOW_SIZE: (u32, u32) = (1920, 1080);
+
+/// Build the WebDriver capabilities for the `[webdriver]` config section,
+/// browsing through `proxy` if one is given.
+pub fn webdriver_capabilities(webdriver: &Webdriver,
+                              headless: bool,
+                              proxy: Option<&str>) -> Result<serde_json::Map<String, Json>> {
+    let mut caps = serde_json::Map::new();
+
+    let window_size = webdriver.window_size.or(if headless { Some(HEADLESS_WINDOW_SIZE) } else { None });
+
+    // https://chromedriver.chromium.org/capabilities
+    let mut chrome_args: Vec<String> = Vec::new();
+    if headless {
+        chrome_args.extend(CHROME_HEADLESS_ARGS.iter().map(|arg| arg.to_string()));
+        if proxy.is_none() {
+            chrome_args.extend(CHROME_NO_PROXY_ARGS.iter().map(|arg| arg.to_string()));
+        }
+    }
+    if let Some((width, height)) = window_size {
+        chrome_args.push(format!("--window-size={},{}", width, height));
+    }
+    if let Some(user_agent) = &webdriver.user_agent {
+        chrome_args.push(format!("--user-agent={}", user_agent));
+    }
+    if let Some(profile_dir) = &webdriver.profile_dir {
+        chrome_args.push(format!("--user-data-dir={}", profile_dir.display()));
+    }
+    chrome_args.extend(webdriver.args.iter().cloned());
+
+    let mut chrome_options = serde_json::json!({ "args": chrome_args });
+    if let Some(binary) = &webdriver.binary {
+        chrome_options["binary"] = json!(binary);
+    }
+
+    // https://developer.mozilla.org/en-US/docs/Web/WebDriver/Capabilities/firefoxOptions
+    let mut firefox_args: Vec<String> = Vec::new();
     if headless {
-        let mut caps = serde_json::map::Map::new();
-
-        let chrome_args = serde_json::json!({
-            "args": [
-                "--no-sandbox",
-                "--headless",
-                "--no-proxy-server",
-                "--proxy-server='direct://'",
-                "--proxy-bypass-list=*",
-                "--window-size=1920,1080",
-                "--start-maximized",
-                "--ignore-certificate-errors",
-                "--disable-extensions",
-                "--blink-settings=imagesEnabled=false",
-            ]
-        });
-
-        // https://developer.mozilla.org/en-US/docs/Web/WebDriver/Capabilities/firefoxOptions
-        let firefox_args = serde_json::json!({
-            "args": [
-                "-headless",
-            ]
-        });
-
-        caps.insert("goog:chromeOptions".to_string(), chrome_args);
-        caps.insert("moz:firefoxOptions".to_string(), firefox_args);
-
-        client.capabilities(caps);
-    }
-
-    let mut client = client.connect(hostname).await?;
+        firefox_args.push("-headless".to_string());
+    }
+    if let Some((width, height)) = window_size {
+        firefox_args.push(format!("--width={}", width));
+        firefox_args.push(format!("--height={}", height));
+    }
+    if let Some(profile_dir) = &webdriver.profile_dir {
+        firefox_args.push("-profile".to_string());
+        firefox_args.push(profile_dir.display().to_string());
+    }
+    firefox_args.extend(webdriver.args.iter().cloned());
+
+    let mut firefox_options = serde_json::json!({ "args": firefox_args });
+    if let Some(binary) = &webdriver.binary {
+        firefox_options["binary"] = json!(binary);
+    }
+    if let Some(user_agent) = &webdriver.user_agent {
+        firefox_options["prefs"] = json!({ "general.useragent.override": user_agent });
+    }
+
+    match webdriver.browser {
+        Some(Browser::Chrome) => {
+            caps.insert("browserName".to_string(), json!("chrome"));
+            caps.insert("goog:chromeOptions".to_string(), chrome_options);
+        }
+        Some(Browser::Firefox) => {
+            caps.insert("browserName".to_string(), json!("firefox"));
+            caps.insert("moz:firefoxOptions".to_string(), firefox_options);
+        }
+        None => {
+            // Each driver ignores the other browser's options
+            caps.insert("goog:chromeOptions".to_string(), chrome_options);
+            caps.insert("moz:firefoxOptions".to_string(), firefox_options);
+        }
+    }
+
+    if let Some(strategy) = webdriver.page_load_strategy {
+        let strategy = match strategy {
+            PageLoadStrategy::Normal => "normal",
+            PageLoadStrategy::Eager => "eager",
+            PageLoadStrategy::None => "none",
+        };
+        caps.insert("pageLoadStrategy".to_string(), json!(strategy));
+    }
+
+    if let Some(proxy) = proxy {
+        caps.insert("proxy".to_string(), proxy::webdriver_proxy(proxy)?);
+    }
+
+    Ok(caps)
+}
+
+/// Creates a new Webdriver client, optionally browsing through `proxy`
+pub async fn new_webdriver_client(config: &Config,
+                                  service: &mut WebdriverService,
+                                  headless: bool,
+                                  proxy: Option<&str>) -> Result<fantoccini::Client> {
+    let hostname = service.url().await?;
+
+    let mut client = fantoccini::ClientBuilder::native();
+    client.capabilities(webdriver_capabilities(&config.webdriver, headless, proxy)?);
+
+    let mut client = client.connect(&hostname).await?;
 
     log::debug!("Connected to WebDriver - session ID: {}", client.session_id().await?.unwrap());
 
     Ok(client)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_webdriver_capabilities() {
+        let webdriver = Webdriver {
+            browser: Some(Browser::Firefox),
+            binary: Some(PathBuf::from("/usr/lib/firefox/firefox")),
+            profile_dir: Some(PathBuf::from("/config/profile")),
+            args: vec!["-private".to_string()],
+            window_size: Some((1280, 720)),
+            user_agent: Some("bestbot".to_string()),
+            page_load_strategy: Some(PageLoadStrategy::Eager),
+            ..Webdriver::default()
+        };
+
+        let caps = webdriver_capabilities(&webdriver, false, None).unwrap();
+        assert_eq!(Json::Object(caps), json!({
+            "browserName": "firefox",
+            "moz:firefoxOptions": {
+                "args": ["--width=1280", "--height=720", "-profile", "/config/profile", "-private"],
+                "binary": "/usr/lib/firefox/firefox",
+                "prefs": { "general.useragent.override": "bestbot" },
+            },
+            "pageLoadStrategy": "eager",
+        }));
+
+        // Without a browser, both are configured as before
+        let caps = webdriver_capabilities(&Webdriver::default(), true, None).unwrap();
+        let chrome_args = caps["goog:chromeOptions"]["args"].as_array().unwrap();
+        assert!(chrome_args.contains(&json!("--headless")));
+        assert!(chrome_args.contains(&json!("--window-size=1920,1080")));
+        assert!(chrome_args.contains(&json!("--no-proxy-server")));
+        assert_eq!(caps["moz:firefoxOptions"]["args"][0], "-headless");
+        assert!(caps.get("browserName").is_none());
+        assert!(caps.get("proxy").is_none());
+
+        // A proxy replaces Chrome's direct connection
+        let caps = webdriver_capabilities(&Webdriver::default(), true, Some("http://127.0.0.1:8080")).unwrap();
+        let chrome_args = caps["goog:chromeOptions"]["args"].as_array().unwrap();
+        assert!(!chrome_args.iter().any(|arg| arg.as_str().unwrap().starts_with("--proxy")));
+        assert!(!chrome_args.contains(&json!("--no-proxy-server")));
+        assert_eq!(caps["proxy"]["proxyType"], "manual");
+    }
+}
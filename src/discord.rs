@@ -34,4 +34,25 @@ impl DiscordWebhook {
 
         Ok(())
     }
+
+    /// Trigger the webhook with a PNG image attached.
+    pub async fn trigger_with_image(&self, message: &str, png: &[u8]) -> Result<()> {
+        let json = serde_json::json!({ "content": message });
+
+        let image = reqwest::multipart::Part::bytes(png.to_vec())
+            .file_name("screenshot.png")
+            .mime_str("image/png")?;
+        let form = reqwest::multipart::Form::new()
+            .text("payload_json", json.to_string())
+            .part("file", image);
+
+        self.client
+            .post(&self.webhook_url)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
 }
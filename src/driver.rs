@@ -0,0 +1,169 @@
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde_json::Value as Json;
+use tokio::process::{Child, Command};
+use tokio::time::sleep;
+
+use crate::config::Config;
+
+static DEFAULT_HOSTNAME: &str = "http://localhost:4444";
+
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A WebDriver server, either started and supervised by us or running
+/// externally at `general.hostname`.
+pub struct WebdriverService {
+    driver: Option<PathBuf>,
+    driver_args: Vec<String>,
+    hostname: String,
+    process: Option<Child>,
+}
+
+impl WebdriverService {
+    pub fn from_config(config: &Config) -> Self {
+        let hostname = config.general.hostname.as_deref().unwrap_or(DEFAULT_HOSTNAME);
+
+        if config.webdriver.driver.is_some() && config.general.hostname.is_some() {
+            log::warn!("Both `webdriver.driver` and `general.hostname` are set, ignoring `general.hostname`");
+        }
+
+        Self {
+            driver: config.webdriver.driver.clone(),
+            driver_args: config.webdriver.driver_args.clone(),
+            hostname: hostname.to_string(),
+            process: None,
+        }
+    }
+
+    /// Returns the URL of a running WebDriver server, (re)starting the
+    /// managed driver if it isn't running.
+    pub async fn url(&mut self) -> Result<String> {
+        let driver = match &self.driver {
+            Some(driver) => driver.clone(),
+            None => return Ok(self.hostname.clone()),
+        };
+
+        if let Some(process) = self.process.as_mut() {
+            match process.try_wait()? {
+                None => return Ok(self.hostname.clone()),
+                Some(status) => log::warn!("WebDriver {} exited ({}), restarting it", driver.display(), status),
+            }
+        }
+
+        self.start(&driver).await?;
+
+        Ok(self.hostname.clone())
+    }
+
+    async fn start(&mut self, driver: &Path) -> Result<()> {
+        let port = free_port()?;
+
+        log::debug!("Starting WebDriver {} on port {}", driver.display(), port);
+
+        let process = Command::new(driver)
+            .arg(format!("--port={}", port))
+            .args(&self.driver_args)
+            .stdin(Stdio::null())
+            // Keep stdout free for `--output json`
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::format_err!("Failed to start WebDriver {}: {}", driver.display(), e))?;
+
+        self.process = Some(process);
+        self.hostname = format!("http://127.0.0.1:{}", port);
+
+        self.wait_until_ready().await?;
+
+        log::info!("Started WebDriver {} at {}", driver.display(), self.hostname);
+
+        Ok(())
+    }
+
+    /// Poll the driver's `/status` endpoint until it accepts new sessions.
+    async fn wait_until_ready(&mut self) -> Result<()> {
+        let client = reqwest::Client::new();
+        let endpoint = format!("{}/status", self.hostname);
+        let deadline = Instant::now() + READY_TIMEOUT;
+
+        loop {
+            if let Some(status) = self.process.as_mut().and_then(|process| process.try_wait().transpose()) {
+                anyhow::bail!("WebDriver exited before it was ready ({})", status?);
+            }
+
+            if let Ok(resp) = client.get(&endpoint).send().await {
+                if let Ok(status) = resp.json::<Json>().await {
+                    if status["value"]["ready"].as_bool().unwrap_or(false) {
+                        return Ok(());
+                    }
+                }
+            }
+
+            if Instant::now() >= deadline {
+                self.shutdown().await;
+                anyhow::bail!("WebDriver was not ready after {:?}", READY_TIMEOUT);
+            }
+
+            sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Returns true if the managed driver was started and has since exited.
+    pub fn has_exited(&mut self) -> bool {
+        match self.process.as_mut().map(Child::try_wait) {
+            Some(Ok(Some(status))) => {
+                log::warn!("WebDriver at {} exited ({})", self.hostname, status);
+                true
+            }
+            Some(Err(e)) => {
+                log::warn!("Failed to check on WebDriver at {}: {}", self.hostname, e);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Stop the managed driver, if any. It is also killed when dropped.
+    pub async fn shutdown(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            log::debug!("Stopping WebDriver at {}", self.hostname);
+            if let Err(e) = process.kill().await {
+                log::warn!("Failed to stop WebDriver: {}", e);
+            }
+        }
+    }
+}
+
+/// Ask the OS for a port that is free right now.
+fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_has_exited() {
+        let mut service = WebdriverService {
+            driver: Some(PathBuf::from("true")),
+            driver_args: Vec::new(),
+            hostname: DEFAULT_HOSTNAME.to_string(),
+            process: None,
+        };
+        assert!(!service.has_exited());
+
+        service.process = Some(Command::new("true").spawn().unwrap());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !service.has_exited() {
+            assert!(Instant::now() < deadline, "The process never exited");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
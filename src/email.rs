@@ -0,0 +1,134 @@
+use anyhow::Result;
+use mailparse::{MailHeaderMap, ParsedMail};
+use regex::Regex;
+
+static CODE_HTML_PAT: &str = r#"<span[^>]*>\s*(\d{4,8})\s*</span>"#;
+static CODE_TEXT_PAT: &str = r#"(?i)code\D{0,40}?\b(\d{4,8})\b"#;
+
+/// The decoded headers and text parts of an email.
+#[derive(Debug)]
+pub struct Email {
+    pub subject: String,
+    /// Seconds since the epoch, from the `Date` header
+    pub date: Option<i64>,
+    pub html: Vec<String>,
+    pub text: Vec<String>,
+}
+
+impl Email {
+    /// Parse a raw RFC 822 message, decoding every `text/html` and
+    /// `text/plain` part.
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        let mail = mailparse::parse_mail(raw)?;
+
+        let subject = mail.headers.get_first_value("Subject").unwrap_or_default();
+        let date = mail.headers
+            .get_first_value("Date")
+            .and_then(|date| mailparse::dateparse(&date).ok());
+
+        let mut email = Self {
+            subject,
+            date,
+            html: Vec::new(),
+            text: Vec::new(),
+        };
+
+        email.add_parts(&mail)?;
+
+        Ok(email)
+    }
+
+    fn add_parts(&mut self, part: &ParsedMail) -> Result<()> {
+        match part.ctype.mimetype.as_str() {
+            "text/html" => self.html.push(part.get_body()?),
+            "text/plain" => self.text.push(part.get_body()?),
+            _ => (),
+        }
+
+        for subpart in &part.subparts {
+            self.add_parts(subpart)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the plain text body, or the HTML body with tags removed if the
+    /// email has no text part.
+    pub fn body_text(&self) -> String {
+        if !self.text.is_empty() {
+            return self.text.join("\n");
+        }
+
+        let tag_pat = Regex::new(r#"(?s)<[^>]*>"#).unwrap();
+        self.html
+            .iter()
+            .map(|body| {
+                tag_pat
+                    .replace_all(body, "\n")
+                    .replace("&nbsp;", " ")
+                    .replace("&amp;", "&")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Find a verification code in the email, preferring the HTML body.
+    pub fn verification_code(&self) -> Option<String> {
+        let html_pat = Regex::new(CODE_HTML_PAT).unwrap();
+        let text_pat = Regex::new(CODE_TEXT_PAT).unwrap();
+
+        let html_codes = self.html.iter().filter_map(|body| html_pat.captures(body));
+        let text_codes = self.text.iter().filter_map(|body| text_pat.captures(body));
+
+        html_codes
+            .chain(text_codes)
+            .next()
+            .map(|captures| captures[1].to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static VERIFICATION_EMAIL: &str = "From: Best Buy <BestBuyInfo@emailinfo.bestbuy.com>\r
+To: someone@gmail.com\r
+Subject: Your Best Buy Verification Code\r
+Date: Tue, 25 May 2021 14:03:10 -0400\r
+MIME-Version: 1.0\r
+Content-Type: multipart/alternative; boundary=\"BOUNDARY\"\r
+\r
+--BOUNDARY\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+Your verification code is 482913.\r
+--BOUNDARY\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<html><body><p>Here's your code:</p><span style=\"font-size:24px\">482913</span></body></html>\r
+--BOUNDARY--\r
+";
+
+    #[test]
+    fn test_parse_verification_email() {
+        let email = Email::parse(VERIFICATION_EMAIL.as_bytes()).unwrap();
+
+        assert_eq!(email.subject, "Your Best Buy Verification Code");
+        assert_eq!(email.date, Some(1621965790));
+        assert_eq!(email.html.len(), 1);
+        assert_eq!(email.text.len(), 1);
+        assert_eq!(email.verification_code(), Some("482913".to_string()));
+    }
+
+    #[test]
+    fn test_text_only_code() {
+        let email = Email {
+            subject: String::new(),
+            date: None,
+            html: Vec::new(),
+            text: vec!["Order 12345 shipped. Your verification code: 771204".to_string()],
+        };
+
+        assert_eq!(email.verification_code(), Some("771204".to_string()));
+    }
+}
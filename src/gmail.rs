@@ -1,27 +1,139 @@
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 
-use anyhow::Result;
-use google_gmail1::{Gmail, api::Message};
+use anyhow::{Context, Result};
+use google_gmail1::{Gmail, api::{Message, Scope}};
 use hyper::Client;
 use hyper_rustls::HttpsConnector;
 use yup_oauth2::InstalledFlowAuthenticator;
+use yup_oauth2::authenticator_delegate::InstalledFlowDelegate;
 
 use crate::config;
 
+static APP_SECRET_NAME: &str = "gmail-api-secret.json";
+
 pub struct GmailClient {
     client: google_gmail1::Gmail,
 }
 
+/// Refuses to start an interactive sign in while the bot is running, so a
+/// missing or revoked token fails instead of waiting on a browser.
+struct NoninteractiveDelegate;
+
+impl InstalledFlowDelegate for NoninteractiveDelegate {
+    fn present_user_url<'a>(
+        &'a self,
+        _url: &'a str,
+        _need_code: bool,
+    ) -> Pin<Box<dyn Future<Output = std::result::Result<String, String>> + Send + 'a>> {
+        Box::pin(async {
+            Err("the Gmail token is missing or revoked, run `bestbot gmail-auth` to sign in again".to_string())
+        })
+    }
+}
+
+/// Prints the sign in URL and reads the authorization code from stdin, for
+/// machines without a browser.
+struct ManualCodeDelegate;
+
+impl InstalledFlowDelegate for ManualCodeDelegate {
+    fn present_user_url<'a>(
+        &'a self,
+        url: &'a str,
+        _need_code: bool,
+    ) -> Pin<Box<dyn Future<Output = std::result::Result<String, String>> + Send + 'a>> {
+        Box::pin(async move {
+            println!("Open this URL in a browser on any machine and sign in:\n\n{}\n", url);
+            println!("Then paste the code Google shows, or the address it redirected to:");
+
+            let mut input = String::new();
+            std::io::stdin().read_line(&mut input).map_err(|e| e.to_string())?;
+
+            parse_authorization_code(&input).ok_or_else(|| "no authorization code was entered".to_string())
+        })
+    }
+}
+
+/// Get the authorization code from what was pasted: either the code itself
+/// or the redirect URL with a `code` parameter.
+fn parse_authorization_code(input: &str) -> Option<String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+
+    match reqwest::Url::parse(input) {
+        Ok(url) => url.query_pairs().find(|(name, _)| name == "code").map(|(_, code)| code.into_owned()),
+        Err(_) => Some(input.to_string()),
+    }
+}
+
+/// Sign in to Gmail interactively and save the token to `token_persist_path`.
+///
+/// By default, a URL is printed to open in a browser on this machine, and
+/// Google redirects to a local port with the authorization code. This needs a
+/// "Desktop app" OAuth client. With `manual`, e.g., on a headless server, the
+/// URL can be opened on any machine, and the code is pasted back on stdin.
+pub async fn authorize<P: AsRef<Path>, Q: Into<PathBuf>>(app_secret_path: P, token_persist_path: Q, manual: bool) -> Result<()> {
+    let secret = yup_oauth2::read_application_secret(app_secret_path).await?;
+    let scopes = [Scope::Readonly.as_ref()];
+
+    let auth = if manual {
+        InstalledFlowAuthenticator::builder(
+            secret,
+            yup_oauth2::InstalledFlowReturnMethod::Interactive,
+        )
+        .persist_tokens_to_disk(token_persist_path)
+        .flow_delegate(Box::new(ManualCodeDelegate))
+        .build()
+        .await?
+    } else {
+        InstalledFlowAuthenticator::builder(
+            secret,
+            yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+        )
+        .persist_tokens_to_disk(token_persist_path)
+        .build()
+        .await?
+    };
+    auth.token(&scopes).await?;
+
+    Ok(())
+}
+
+/// Returns the paths of the app secret and of the token for a Gmail user.
+pub fn token_paths(config: &config::Config, username: &str) -> (PathBuf, PathBuf) {
+    let default_working_dir = "".to_string();
+    let working_dir = config.general.working_dir.as_ref().unwrap_or(&default_working_dir);
+
+    let token_persist_name = format!("{}-token.json", username);
+
+    let app_secret_path = PathBuf::new().join(working_dir).join(APP_SECRET_NAME);
+    let token_persist_path = PathBuf::new().join(working_dir).join(token_persist_name);
+
+    (app_secret_path, token_persist_path)
+}
+
 impl GmailClient {
     pub async fn new<P: AsRef<Path>, Q: Into<PathBuf>>(app_secret_path: P, token_persist_path: Q) -> Result<Self> {
+        let token_persist_path = token_persist_path.into();
+        if !token_persist_path.exists() {
+            anyhow::bail!(
+                "Gmail token {} not found, run `bestbot gmail-auth` to create it",
+                token_persist_path.display(),
+            );
+        }
+
         let secret = yup_oauth2::read_application_secret(app_secret_path).await?;
 
         let auth =
             InstalledFlowAuthenticator::builder(
                 secret,
-                yup_oauth2::InstalledFlowReturnMethod::HTTPRedirect,
+                yup_oauth2::InstalledFlowReturnMethod::Interactive,
             )
             .persist_tokens_to_disk(token_persist_path)
+            .flow_delegate(Box::new(NoninteractiveDelegate))
             .build()
             .await?;
 
@@ -32,43 +144,34 @@ impl GmailClient {
         })
     }
 
-    /// Constructs a GmailClient from a Config.
-    pub async fn from_config(config: &config::Config) -> Result<Option<Self>> {
-        let default_working_dir = "".to_string();
+    /// Constructs a GmailClient for the given account's Gmail user from a Config.
+    pub async fn from_config(config: &config::Config, account: &config::Account) -> Result<Option<Self>> {
+        let username = match config.gmail_user(account) {
+            Some(username) => username,
+            None => return Ok(None),
+        };
 
-        if config.general.gmail_user.is_none() {
-            return Ok(None);
-        }
-
-        let working_dir = config.general.working_dir.as_ref().unwrap_or(&default_working_dir);
-        let username = &config.general.gmail_user.as_ref().unwrap();
-
-        let app_secret_name = "gmail-api-secret.json";
-        let token_persist_name = format!("{}-token.json", username);
-
-        let app_secret_path = PathBuf::new().join(working_dir).join(app_secret_name);
-        let token_persist_path = PathBuf::new().join(working_dir).join(token_persist_name);
+        let (app_secret_path, token_persist_path) = token_paths(config, username);
 
         let gmail_client = GmailClient::new(&app_secret_path, &token_persist_path).await?;
 
         Ok(Some(gmail_client))
     }
 
-    /// List the first `limit` messages that match the given query.
+    /// List the first `limit` messages that match the given query, newest first.
     pub async fn list_messages(&self, user_id: &str, query: &str, limit: Option<u32>) -> Result<Vec<Message>> {
         let (_, response) = self.client
             .users()
             .messages_list(user_id)
-            .add_scope(google_gmail1::api::Scope::Readonly)
+            .add_scope(Scope::Readonly)
             .q(query)
             .max_results(limit.unwrap_or(20))
             .include_spam_trash(false)
             .doit()
-            .await?;
+            .await
+            .context("Failed to list Gmail messages, the token may have been revoked")?;
 
-        let messages = response.messages.ok_or_else(|| anyhow::format_err!("No messages found"))?;
-
-        Ok(messages)
+        Ok(response.messages.unwrap_or_default())
     }
 
     /// Get the full content for a single Gmail message.
@@ -76,19 +179,37 @@ impl GmailClient {
         let (_, message) = self.client
             .users()
             .messages_get(user_id, message_id)
-            .add_scope(google_gmail1::api::Scope::Readonly)
+            .add_scope(Scope::Readonly)
             .format(format)
             .doit()
             .await?;
         Ok(message)
     }
 
-    pub async fn get_message_body(&self, user_id: &str, message_id: &str) -> Result<String> {
+    /// Get the raw RFC 822 content of a single Gmail message.
+    pub async fn get_raw_message(&self, user_id: &str, message_id: &str) -> Result<Vec<u8>> {
         let message = self.get_message(user_id, message_id, "RAW").await?;
-        let raw = message.raw.as_ref().unwrap();
+        let raw = message.raw
+            .as_ref()
+            .ok_or_else(|| anyhow::format_err!("Gmail message {} has no raw content", message_id))?;
         let config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
         let decoded = base64::decode_config(raw, config)?;
-        let body = String::from_utf8(decoded)?;
-        Ok(body)
+        Ok(decoded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_authorization_code() {
+        assert_eq!(parse_authorization_code("4/0AX4XfWh-code\n"), Some("4/0AX4XfWh-code".to_string()));
+        assert_eq!(
+            parse_authorization_code("http://localhost:8080/?code=4/0AX4XfWh-code&scope=https://www.googleapis.com/auth/gmail.readonly"),
+            Some("4/0AX4XfWh-code".to_string()),
+        );
+        assert_eq!(parse_authorization_code("http://localhost:8080/?error=access_denied"), None);
+        assert_eq!(parse_authorization_code("  \n"), None);
     }
 }
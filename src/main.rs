@@ -4,16 +4,33 @@ use anyhow::Result;
 use structopt::StructOpt;
 
 mod bestbuy;
+mod challenge;
+mod commands;
 mod common;
 mod config;
 mod discord;
+mod driver;
+mod email;
 mod gmail;
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod mock_server;
+#[cfg(test)]
+mod mock_webdriver;
+mod money;
+mod notifier;
+mod orders;
+mod output;
+mod proxy;
+mod reload;
+mod secret;
+mod throttle;
 mod twilio;
+mod verification;
 
-use bestbuy::BestBuyBot;
-use discord::DiscordWebhook;
-use gmail::GmailClient;
-use twilio::TwilioClient;
+use commands::{Command, ConfigCommand, SelectorsCommand};
+use output::{Output, OutputFormat};
 
 #[derive(StructOpt)]
 struct Args {
@@ -22,6 +39,14 @@ struct Args {
     dry_run: bool,
     #[structopt(long)]
     headless: bool,
+    /// Account to use for single-account commands (defaults to the first)
+    #[structopt(long)]
+    account: Option<String>,
+    /// Print results and events as "text" or JSON lines ("json")
+    #[structopt(long, default_value = "text")]
+    output: OutputFormat,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
 #[tokio::main]
@@ -29,20 +54,41 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     let args = Args::from_args();
-    let config = config::Config::load(args.config_file)?;
+    let command = args.command.unwrap_or(Command::Run);
+
+    if let Command::Config(ConfigCommand::Validate) = command {
+        return commands::validate_config(&args.config_file);
+    }
+
+    let config = config::Config::load(&args.config_file)?;
+    let config_file = &args.config_file;
+    let account = args.account.as_deref();
+    let (dry_run, headless) = (args.dry_run, args.headless);
+    let output = Output::new(args.output);
 
-    let gmail_client = GmailClient::from_config(&config).await?;
-    let twilio_client = TwilioClient::from_config(&config)?;
-    let discord_client = DiscordWebhook::from_config(&config);
+    let run = async {
+        match &command {
+            Command::Run => commands::run(&config, config_file, output, dry_run, headless).await?,
+            Command::Check { skus } => commands::check(&config, account, skus, output).await?,
+            Command::Info { sku } => commands::info(&config, account, sku, output).await?,
+            Command::Cart(cart_command) => commands::cart(&config, account, cart_command, output).await?,
+            Command::Login => commands::login(&config, account, headless, output).await?,
+            Command::NotifyTest => commands::notify_test(&config).await?,
+            Command::GmailAuth { manual } => commands::gmail_auth(&config, account, *manual).await?,
+            Command::Selectors(SelectorsCommand::Check) => {
+                commands::check_selectors(&config, account, headless, output).await?
+            }
+            Command::Config(ConfigCommand::Validate) => unreachable!(),
+        }
 
-    let mut bot = BestBuyBot::new(
-        &config,
-        gmail_client.as_ref(),
-        twilio_client.as_ref(),
-        discord_client.as_ref()
-    );
+        Ok::<(), anyhow::Error>(())
+    };
 
-    bot.start(args.dry_run, args.headless).await?;
+    // Dropping the running command also stops any managed WebDriver
+    tokio::select! {
+        result = run => result?,
+        _ = tokio::signal::ctrl_c() => log::info!("Interrupted, shutting down"),
+    }
 
     Ok(())
 }
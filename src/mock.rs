@@ -0,0 +1,436 @@
+//! A local stand-in for the Best Buy API, used to test `BestBuyApi` and the
+//! bot loop without network access.
+//!
+//! A `Scenario` scripts the server's behavior: the items it sells, when they
+//! come in stock, throttling, server errors, slow responses and expiring
+//! sessions.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+use reqwest::Url;
+use serde_json::{json, Value as Json};
+
+use crate::mock_server::{MockResponse, MockServer};
+
+#[derive(Clone, Debug)]
+struct MockItem {
+    sku: String,
+    name: String,
+    price: f64,
+    /// Price in the cart, if different from the product page
+    cart_price: Option<f64>,
+    /// Number of stock checks that report the item as sold out
+    sold_out_checks: u32,
+    quantity_limit: u32,
+}
+
+/// What the mock server sells and how it misbehaves.
+#[derive(Clone, Debug)]
+pub struct Scenario {
+    items: Vec<MockItem>,
+    too_many_requests: u32,
+    server_errors: u32,
+    auth_expires_after: Option<u32>,
+    order_delay: Option<Duration>,
+    order_fees: f64,
+    credit_card: bool,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            too_many_requests: 0,
+            server_errors: 0,
+            auth_expires_after: None,
+            order_delay: None,
+            order_fees: 0.0,
+            credit_card: true,
+        }
+    }
+}
+
+impl Scenario {
+    /// Sell an item, in stock right away with a quantity limit of 2.
+    pub fn item(mut self, sku: &str, name: &str, price: f64) -> Self {
+        self.items.push(MockItem {
+            sku: sku.to_string(),
+            name: name.to_string(),
+            price,
+            cart_price: None,
+            sold_out_checks: 0,
+            quantity_limit: 2,
+        });
+        self
+    }
+
+    fn item_mut(&mut self, sku: &str) -> &mut MockItem {
+        self.items
+            .iter_mut()
+            .find(|item| item.sku == sku)
+            .unwrap_or_else(|| panic!("Unknown mock item {}", sku))
+    }
+
+    /// Report the item as sold out for the first `checks` stock checks, and
+    /// refuse to add it to the cart until then.
+    pub fn in_stock_after(mut self, sku: &str, checks: u32) -> Self {
+        self.item_mut(sku).sold_out_checks = checks;
+        self
+    }
+
+    /// Charge `price` for the item once it's in the cart.
+    pub fn cart_price(mut self, sku: &str, price: f64) -> Self {
+        self.item_mut(sku).cart_price = Some(price);
+        self
+    }
+
+    /// Answer the first `count` requests with a 429.
+    pub fn too_many_requests(mut self, count: u32) -> Self {
+        self.too_many_requests = count;
+        self
+    }
+
+    /// Answer the first `count` requests (after any 429s) with a 503.
+    pub fn server_errors(mut self, count: u32) -> Self {
+        self.server_errors = count;
+        self
+    }
+
+    /// Reject cart and checkout requests with a 401 once `requests`
+    /// requests were served, as if the session expired.
+    pub fn auth_expires_after(mut self, requests: u32) -> Self {
+        self.auth_expires_after = Some(requests);
+        self
+    }
+
+    /// Place orders right away but answer only after `delay`, as if the
+    /// response was lost.
+    pub fn order_delay(mut self, delay: Duration) -> Self {
+        self.order_delay = Some(delay);
+        self
+    }
+
+    /// Add `fees`, e.g., taxes, to the order total of a non-empty cart.
+    pub fn order_fees(mut self, fees: f64) -> Self {
+        self.order_fees = fees;
+        self
+    }
+
+    pub fn without_credit_card(mut self) -> Self {
+        self.credit_card = false;
+        self
+    }
+}
+
+struct LineItem {
+    id: String,
+    sku: String,
+    quantity: u32,
+}
+
+struct State {
+    scenario: Scenario,
+    requests: Vec<String>,
+    stock_checks: HashMap<String, u32>,
+    cart: Vec<LineItem>,
+    next_id: u32,
+    checkouts: Vec<String>,
+    orders: Vec<String>,
+}
+
+impl State {
+    fn item(&self, sku: &str) -> Option<&MockItem> {
+        self.scenario.items.iter().find(|item| item.sku == sku)
+    }
+
+    fn is_in_stock(&self, sku: &str) -> bool {
+        match self.item(sku) {
+            Some(item) => self.stock_checks.get(sku).copied().unwrap_or(0) >= item.sold_out_checks,
+            None => false,
+        }
+    }
+
+    fn respond(&mut self, method: &Method, path: &str, query: &HashMap<String, String>, body: &Json) -> MockResponse {
+        self.requests.push(format!("{} {}", method, path));
+
+        if self.scenario.too_many_requests > 0 {
+            self.scenario.too_many_requests -= 1;
+            return (StatusCode::TOO_MANY_REQUESTS, json!({"error": "Too Many Requests"}));
+        }
+        if self.scenario.server_errors > 0 {
+            self.scenario.server_errors -= 1;
+            return (StatusCode::SERVICE_UNAVAILABLE, json!({"error": "Service Unavailable"}));
+        }
+
+        let needs_auth = path.starts_with("/cart") || path.starts_with("/basket") || path.starts_with("/checkout");
+        let expired = matches!(self.scenario.auth_expires_after, Some(after) if self.requests.len() as u32 > after);
+        if needs_auth && expired {
+            return (StatusCode::UNAUTHORIZED, json!({"error": "Unauthorized"}));
+        }
+
+        let sku = query.get("skuId").cloned().unwrap_or_default();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            (&Method::GET, ["pricing", "v1", "price", "item"]) => self.price(&sku),
+            (&Method::GET, ["api", "tcfb", "model.json"]) => self.model(query),
+            (&Method::GET, ["site", "canopy", "component", "fulfillment", "add-to-cart-button", "v1"]) => {
+                let button = if self.is_in_stock(&sku) { "Add to Cart" } else { "Sold Out" };
+                *self.stock_checks.entry(sku).or_insert(0) += 1;
+                (StatusCode::OK, json!(format!(r#"<button class="add-to-cart-button">{}</button>"#, button)))
+            }
+            (&Method::GET, ["basket", "v1", "basketCount"]) => {
+                let count: u32 = self.cart.iter().map(|line_item| line_item.quantity).sum();
+                (StatusCode::OK, json!({"count": count}))
+            }
+            (&Method::POST, ["cart", "api", "v1", "addToCart"]) => self.add_to_cart(body),
+            (&Method::GET, ["cart", "json"]) => (StatusCode::OK, json!({"cart": self.cart_json()})),
+            (&Method::PUT, ["cart", "item", id]) => {
+                let quantity = body["quantity"].as_u64().unwrap_or(1) as u32;
+                let i = match self.cart.iter().position(|line_item| line_item.id == *id) {
+                    Some(i) => i,
+                    None => return (StatusCode::NOT_FOUND, json!({"error": "Not Found"})),
+                };
+
+                if quantity > self.item(&self.cart[i].sku).map_or(1, |item| item.quantity_limit) {
+                    return (StatusCode::BAD_REQUEST, json!({"error": "Quantity limit exceeded"}));
+                }
+
+                self.cart[i].quantity = quantity;
+                (StatusCode::OK, json!({}))
+            }
+            (&Method::DELETE, ["cart", "item", id]) => {
+                self.cart.retain(|line_item| line_item.id != *id);
+                (StatusCode::OK, json!({}))
+            }
+            (&Method::POST, ["cart", "checkout"]) => {
+                let order_id = format!("MOCK-ORDER-{}", self.checkouts.len() + 1);
+                self.checkouts.push(order_id.clone());
+                (StatusCode::OK, json!({"updateData": {"order": {"id": order_id}}}))
+            }
+            (&Method::POST, ["checkout", "orders", order_id]) if self.checkouts.iter().any(|id| id == order_id) => {
+                self.orders.push(order_id.to_string());
+                self.cart.clear();
+                (StatusCode::OK, json!({"state": "SUBMITTED"}))
+            }
+            _ => (StatusCode::NOT_FOUND, json!({"error": "Not Found"})),
+        }
+    }
+
+    fn price(&self, sku: &str) -> MockResponse {
+        match self.item(sku) {
+            Some(item) => (StatusCode::OK, json!({
+                "regularPrice": item.price,
+                "currentPrice": item.price,
+                "customerPrice": item.price,
+            })),
+            None => (StatusCode::NOT_FOUND, json!({"error": "Not Found"})),
+        }
+    }
+
+    /// Answer the `jsonGraph` query made by `BestBuyApi::get_item_info`.
+    fn model(&self, query: &HashMap<String, String>) -> MockResponse {
+        let paths: Json = query.get("paths").and_then(|paths| serde_json::from_str(paths).ok()).unwrap_or_default();
+        let sku = match &paths[0][5] {
+            Json::Number(sku) => sku.to_string(),
+            Json::String(sku) => sku.clone(),
+            _ => return (StatusCode::BAD_REQUEST, json!({"error": "Bad Request"})),
+        };
+
+        let item = match self.item(&sku) {
+            Some(item) => item,
+            None => return (StatusCode::OK, json!({"jsonGraph": {}})),
+        };
+
+        (StatusCode::OK, json!({
+            "jsonGraph": {
+                "shop": {
+                    "magellan": {
+                        "v1": {"sites": {"skuId": {(sku.clone()): {"sites": {"bbypres": {
+                            "relativePdpUrl": {"value": format!("/site/mock/{}.p?skuId={}", sku, sku)},
+                        }}}}}},
+                        "v2": {"product": {"skus": {(sku.clone()): {
+                            "names": {"short": {"value": item.name}},
+                            "images": {"0": {"value": {"href": format!("https://pisces.bbystatic.com/{}.jpg", sku)}}},
+                            "descriptions": {"long": {"value": format!("The {}", item.name)}},
+                        }}}},
+                    },
+                },
+            },
+        }))
+    }
+
+    fn add_to_cart(&mut self, body: &Json) -> MockResponse {
+        let sku = body["items"][0]["skuId"].as_str().unwrap_or_default().to_string();
+        if !self.is_in_stock(&sku) {
+            return (StatusCode::BAD_REQUEST, json!({"errorSummary": {"errorCode": "ITEM_NOT_SELLABLE"}}));
+        }
+
+        match self.cart.iter_mut().find(|line_item| line_item.sku == sku) {
+            Some(line_item) => line_item.quantity += 1,
+            None => {
+                self.next_id += 1;
+                self.cart.push(LineItem {
+                    id: format!("line-{}", self.next_id),
+                    sku,
+                    quantity: 1,
+                });
+            }
+        }
+
+        (StatusCode::OK, json!({"cartCount": self.cart.len()}))
+    }
+
+    fn cart_json(&self) -> Json {
+        let shipping = json!({
+            "typeCode": "SHIPPING",
+            "zipcode": "10001",
+            "minDate": 1622160000000u64,
+            "daysTillFulfillment": 3,
+            "maxDate": 1622419200000u64,
+            "price": "FREE",
+            "selected": true,
+            "isPreOrder": false,
+        });
+
+        let mut total = 0.0;
+        let line_items: Vec<Json> = self.cart
+            .iter()
+            .map(|line_item| {
+                let item = self.item(&line_item.sku).unwrap();
+                let price = item.cart_price.unwrap_or(item.price);
+                let line_price = price * line_item.quantity as f64;
+                total += line_price;
+
+                json!({
+                    "id": line_item.id,
+                    "quantity": line_item.quantity,
+                    "quantityLimit": item.quantity_limit,
+                    "item": {
+                        "skuId": item.sku,
+                        "shortLabel": item.name,
+                        "imageUrl": format!("https://pisces.bbystatic.com/{}.jpg", item.sku),
+                        "itemUrl": format!("/site/mock/{}.p?skuId={}", item.sku, item.sku),
+                        "fulfillments": [shipping],
+                        "typeCode": "HARDGOOD",
+                        "price": {
+                            "linePrice": format!("${:.2}", line_price),
+                            "regularPrice": format!("${:.2}", price),
+                        },
+                    },
+                    "digital": false,
+                })
+            })
+            .collect();
+
+        let count: u32 = self.cart.iter().map(|line_item| line_item.quantity).sum();
+        let fees = if self.cart.is_empty() { 0.0 } else { self.scenario.order_fees };
+
+        json!({
+            "id": "mock-cart",
+            "cartItemCount": count.to_string(),
+            "subtotalAmount": format!("{:.2}", total),
+            "lineItems": line_items,
+            "fulfillments": if self.cart.is_empty() { json!([]) } else { json!([shipping]) },
+            "orderSummary": {
+                "productTotal": format!("${:.2}", total),
+                "orderTotal": format!("${:.2}", total + fees),
+            },
+            "paypalWalletEnabled": false,
+            "creditCardInProfile": self.scenario.credit_card,
+        })
+    }
+}
+
+async fn handle(state: Arc<Mutex<State>>, parts: Parts, body: Json) -> Response<Body> {
+    let url = Url::parse(&format!("http://mock{}", parts.uri)).unwrap();
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    let (status, json, delay) = {
+        let mut state = state.lock().unwrap();
+        let (status, json) = state.respond(&parts.method, url.path(), &query, &body);
+        let delay = state.scenario.order_delay.filter(|_| url.path().starts_with("/checkout/orders"));
+        (status, json, delay)
+    };
+
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+
+    // The add to cart button is HTML, everything else is JSON
+    let (content_type, body) = match json {
+        Json::String(html) => ("text/html", html),
+        json => ("application/json", json.to_string()),
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header("Content-Type", content_type);
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        response = response.header("Retry-After", "0");
+    }
+
+    response.body(Body::from(body)).unwrap()
+}
+
+/// A mock Best Buy server on a free local port, stopped when dropped.
+pub struct MockBestBuy {
+    server: MockServer,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBestBuy {
+    pub fn start(scenario: Scenario) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            scenario,
+            requests: Vec::new(),
+            stock_checks: HashMap::new(),
+            cart: Vec::new(),
+            next_id: 0,
+            checkouts: Vec::new(),
+            orders: Vec::new(),
+        }));
+
+        let service_state = state.clone();
+        let server = MockServer::start(move |parts, body| handle(service_state.clone(), parts, body));
+
+        Self {
+            server,
+            state,
+        }
+    }
+
+    /// Base URL for `BestBuyApi::with_base_url`.
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// Every request served so far, as `<method> <path>`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn stock_checks(&self, sku: &str) -> u32 {
+        self.state.lock().unwrap().stock_checks.get(sku).copied().unwrap_or(0)
+    }
+
+    /// The SKUs and quantities in the cart.
+    pub fn cart(&self) -> Vec<(String, u32)> {
+        self.state
+            .lock()
+            .unwrap()
+            .cart
+            .iter()
+            .map(|line_item| (line_item.sku.clone(), line_item.quantity))
+            .collect()
+    }
+
+    /// IDs of the orders that were placed.
+    pub fn orders(&self) -> Vec<String> {
+        self.state.lock().unwrap().orders.clone()
+    }
+}
@@ -0,0 +1,73 @@
+//! The HTTP server shared by the mocks in `mock` and `mock_webdriver`.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::channel::oneshot;
+use hyper::http::request::Parts;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use serde_json::Value as Json;
+
+/// A status and JSON body to answer a request with.
+pub type MockResponse = (StatusCode, Json);
+
+/// An HTTP server on a free local port, stopped when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    /// Answer every request with `handle`, given the request and its body
+    /// decoded as JSON. Bodies that aren't JSON are passed as `null`.
+    pub fn start<F, Fut>(handle: F) -> Self
+    where
+        F: Fn(Parts, Json) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Response<Body>> + Send + 'static,
+    {
+        let handle = Arc::new(handle);
+        let make_service = make_service_fn(move |_| {
+            let handle = handle.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let handle = handle.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                        let body: Json = serde_json::from_slice(&body).unwrap_or_default();
+
+                        Ok::<_, Infallible>(handle(parts, body).await)
+                    }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            stopped.await.ok();
+        }));
+
+        Self {
+            addr,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
@@ -0,0 +1,406 @@
+//! A local stand-in for a WebDriver server driving the Best Buy sign-in
+//! page, used to test `WebdriverBot::sign_in` without a browser.
+//!
+//! Only the parts of the W3C WebDriver protocol used by the sign-in flow are
+//! implemented: sessions, navigation, page source, finding elements, typing,
+//! clicking, cookies and screenshots. Pages aren't rendered; elements are
+//! found by exact match on the default `Selectors`.
+
+use std::sync::{Arc, Mutex};
+
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+use serde_json::{json, Value as Json};
+
+use crate::mock_server::{MockResponse, MockServer};
+
+static ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+static SIGN_IN_PATH: &str = "/identity/global/signin";
+static VERIFY_URL: &str = "https://www.bestbuy.com/identity/signin/verify";
+static CHALLENGE_URL: &str = "https://www.bestbuy.com/identity/signin/challenge";
+static SIGNED_IN_URL: &str = "https://www.bestbuy.com/";
+/// A 1x1 PNG
+static SCREENSHOT: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+/// How the sign-in page behaves.
+#[derive(Clone, Debug)]
+pub struct SignIn {
+    username: String,
+    password: String,
+    verification_code: Option<String>,
+    challenge: bool,
+}
+
+impl SignIn {
+    /// Accept `username` and `password`, and sign in right away.
+    pub fn new(username: &str, password: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            password: password.to_string(),
+            verification_code: None,
+            challenge: false,
+        }
+    }
+
+    /// Ask for a verification code after the password, and only accept
+    /// `code`.
+    pub fn verification_code(mut self, code: &str) -> Self {
+        self.verification_code = Some(code.to_string());
+        self
+    }
+
+    /// Show a bot challenge instead of signing in.
+    pub fn challenge(mut self) -> Self {
+        self.challenge = true;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Page {
+    Blank,
+    SignIn,
+    Verification,
+    Challenge,
+    SignedIn,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ElementKind {
+    Username,
+    Password,
+    Submit,
+    VerificationCode,
+    VerificationForm,
+    VerificationSubmit,
+}
+
+impl ElementKind {
+    fn selector(self) -> &'static str {
+        match self {
+            ElementKind::Username => "#fld-e",
+            ElementKind::Password => "#fld-p1",
+            ElementKind::Submit => "div.cia-form__controls > button",
+            ElementKind::VerificationCode => "input#verificationCode",
+            ElementKind::VerificationForm => "form.cia-form",
+            ElementKind::VerificationSubmit => "button[type=submit]",
+        }
+    }
+
+    fn html(self) -> &'static str {
+        match self {
+            ElementKind::Username => r#"<input id="fld-e" type="email">"#,
+            ElementKind::Password => r#"<input id="fld-p1" type="password">"#,
+            ElementKind::Submit => r#"<div class="cia-form__controls"><button>Sign In</button></div>"#,
+            ElementKind::VerificationCode => r#"<input id="verificationCode" type="text">"#,
+            ElementKind::VerificationForm => r#"<form class="cia-form">"#,
+            ElementKind::VerificationSubmit => r#"<button type="submit">Continue</button></form>"#,
+        }
+    }
+}
+
+impl Page {
+    fn elements(self) -> &'static [ElementKind] {
+        match self {
+            Page::SignIn => &[ElementKind::Username, ElementKind::Password, ElementKind::Submit],
+            Page::Verification => &[
+                ElementKind::VerificationForm,
+                ElementKind::VerificationCode,
+                ElementKind::VerificationSubmit,
+            ],
+            _ => &[],
+        }
+    }
+
+    fn html(self) -> String {
+        let (title, body) = match self {
+            Page::Challenge => ("Access Denied", r#"<div id="sec-if-cpt-container"></div>"#.to_string()),
+            page => ("Best Buy", page.elements().iter().map(|kind| kind.html()).collect()),
+        };
+        format!("<html><head><title>{}</title></head><body>{}</body></html>", title, body)
+    }
+}
+
+fn success(value: Json) -> MockResponse {
+    (StatusCode::OK, json!({ "value": value }))
+}
+
+fn error(status: StatusCode, error: &str, message: &str) -> MockResponse {
+    (status, json!({
+        "value": {
+            "error": error,
+            "message": message,
+            "stacktrace": "",
+        }
+    }))
+}
+
+struct State {
+    sign_in: SignIn,
+    session: Option<String>,
+    sessions: u32,
+    url: String,
+    page: Page,
+    /// A navigation started by a click, which completes after the current
+    /// URL is read once more, as if the page were still loading
+    navigation: Option<(String, Page)>,
+    elements: Vec<(String, ElementKind)>,
+    typed: Vec<(String, String)>,
+    visited: Vec<String>,
+    cookies: Vec<Json>,
+}
+
+impl State {
+    fn respond(&mut self, method: &Method, path: &str, body: &Json) -> MockResponse {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        let (session, command) = match (method, segments.as_slice()) {
+            (&Method::POST, ["session"]) => {
+                self.sessions += 1;
+                let session = format!("mock-session-{}", self.sessions);
+                self.session = Some(session.clone());
+                return success(json!({
+                    "sessionId": session,
+                    "capabilities": {"browserName": "mock"},
+                }));
+            }
+            (_, ["session", session, command @ ..]) => (*session, command),
+            _ => return error(StatusCode::NOT_FOUND, "unknown command", path),
+        };
+
+        if self.session.as_deref() != Some(session) {
+            return error(StatusCode::NOT_FOUND, "invalid session id", session);
+        }
+
+        // Anything other than polling the URL finishes loading the page
+        if !matches!((method, command), (&Method::GET, ["url"])) {
+            self.finish_navigation();
+        }
+
+        match (method, command) {
+            (&Method::DELETE, []) => {
+                self.session = None;
+                success(Json::Null)
+            }
+            (&Method::GET, ["url"]) => {
+                let url = self.url.clone();
+                self.finish_navigation();
+                success(json!(url))
+            }
+            (&Method::POST, ["url"]) => {
+                let url = body["url"].as_str().unwrap_or_default().to_string();
+                let page = if url.contains(SIGN_IN_PATH) { Page::SignIn } else { Page::Other };
+                self.load(url, page);
+                success(Json::Null)
+            }
+            (&Method::GET, ["source"]) => success(json!(self.page.html())),
+            (&Method::GET, ["screenshot"]) => success(json!(SCREENSHOT)),
+            (&Method::POST, ["element"]) => self.find(body, None),
+            (&Method::POST, ["elements"]) => {
+                let elements: Vec<Json> = self.find_all(body, None).iter().map(|id| json!({ELEMENT_KEY: id})).collect();
+                success(json!(elements))
+            }
+            (&Method::POST, ["element", parent, "element"]) => self.find(body, Some(*parent)),
+            (_, ["element", id, action @ ..]) => {
+                let kind = match self.elements.iter().find(|(element, _)| element == id) {
+                    Some((_, kind)) => *kind,
+                    None => return error(StatusCode::NOT_FOUND, "stale element reference", id),
+                };
+                self.element_command(method, kind, action, body)
+            }
+            (&Method::GET, ["cookie"]) => success(json!(self.cookies)),
+            (&Method::POST, ["cookie"]) => {
+                let cookie = body["cookie"].clone();
+                self.cookies.retain(|existing| existing["name"] != cookie["name"]);
+                self.cookies.push(cookie);
+                success(Json::Null)
+            }
+            _ => error(StatusCode::NOT_FOUND, "unknown command", path),
+        }
+    }
+
+    fn load(&mut self, url: String, page: Page) {
+        self.visited.push(url.clone());
+        self.url = url;
+        self.page = page;
+        self.navigation = None;
+        self.elements.clear();
+    }
+
+    fn finish_navigation(&mut self) {
+        if let Some((url, page)) = self.navigation.take() {
+            self.load(url, page);
+        }
+    }
+
+    /// Returns the IDs of the elements on the page matching a CSS selector.
+    /// Inside the verification form, only the submit button is found.
+    fn find_all(&mut self, body: &Json, parent: Option<&str>) -> Vec<String> {
+        let selector = body["value"].as_str().unwrap_or_default();
+
+        let kinds: Vec<ElementKind> = match parent {
+            Some(_) if selector.contains("type=submit") => vec![ElementKind::VerificationSubmit],
+            Some(_) => Vec::new(),
+            None => self.page
+                .elements()
+                .iter()
+                .copied()
+                .filter(|kind| kind.selector() == selector)
+                .collect(),
+        };
+
+        kinds
+            .into_iter()
+            .map(|kind| {
+                let id = format!("mock-element-{}", self.elements.len() + 1);
+                self.elements.push((id.clone(), kind));
+                id
+            })
+            .collect()
+    }
+
+    fn find(&mut self, body: &Json, parent: Option<&str>) -> MockResponse {
+        match self.find_all(body, parent).first() {
+            Some(id) => success(json!({ELEMENT_KEY: id})),
+            None => error(StatusCode::NOT_FOUND, "no such element", body["value"].as_str().unwrap_or_default()),
+        }
+    }
+
+    fn element_command(&mut self, method: &Method, kind: ElementKind, action: &[&str], body: &Json) -> MockResponse {
+        match (method, action) {
+            (&Method::POST, ["value"]) => {
+                let text = match body["text"].as_str() {
+                    Some(text) => text.to_string(),
+                    None => body["value"]
+                        .as_array()
+                        .map(|keys| keys.iter().filter_map(Json::as_str).collect())
+                        .unwrap_or_default(),
+                };
+                self.typed.push((kind.selector().to_string(), text));
+                success(Json::Null)
+            }
+            (&Method::POST, ["click"]) => {
+                self.click(kind);
+                success(Json::Null)
+            }
+            (&Method::GET, ["text"]) => success(json!("")),
+            (&Method::GET, ["attribute", _]) => success(Json::Null),
+            _ => error(StatusCode::NOT_FOUND, "unknown command", &action.join("/")),
+        }
+    }
+
+    fn typed_into(&self, kind: ElementKind) -> Option<&str> {
+        self.typed
+            .iter()
+            .rev()
+            .find(|(selector, _)| selector == kind.selector())
+            .map(|(_, text)| text.as_str())
+    }
+
+    fn click(&mut self, kind: ElementKind) {
+        let navigation = match kind {
+            ElementKind::Submit => {
+                let signed_in = self.typed_into(ElementKind::Username) == Some(self.sign_in.username.as_str())
+                    && self.typed_into(ElementKind::Password) == Some(self.sign_in.password.as_str());
+
+                if self.sign_in.challenge {
+                    (CHALLENGE_URL.to_string(), Page::Challenge)
+                } else if !signed_in {
+                    (format!("{}?error=credentials", self.url), Page::SignIn)
+                } else if self.sign_in.verification_code.is_some() {
+                    (VERIFY_URL.to_string(), Page::Verification)
+                } else {
+                    self.set_auth_cookies();
+                    (SIGNED_IN_URL.to_string(), Page::SignedIn)
+                }
+            }
+            ElementKind::VerificationSubmit => {
+                if self.typed_into(ElementKind::VerificationCode) == self.sign_in.verification_code.as_deref() {
+                    self.set_auth_cookies();
+                    (SIGNED_IN_URL.to_string(), Page::SignedIn)
+                } else {
+                    (format!("{}?error=code", VERIFY_URL), Page::Verification)
+                }
+            }
+            _ => return,
+        };
+
+        self.navigation = Some(navigation);
+    }
+
+    fn set_auth_cookies(&mut self) {
+        for name in &["ut", "at"] {
+            self.cookies.push(json!({
+                "name": name,
+                "value": format!("mock-{}", name),
+                "domain": ".bestbuy.com",
+                "path": "/",
+                "secure": true,
+                "httpOnly": false,
+            }));
+        }
+    }
+}
+
+async fn handle(state: Arc<Mutex<State>>, parts: Parts, body: Json) -> Response<Body> {
+    let (status, json) = state.lock().unwrap().respond(&parts.method, parts.uri.path(), &body);
+
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(json.to_string()))
+        .unwrap()
+}
+
+/// A mock WebDriver server on a free local port, stopped when dropped.
+pub struct MockWebdriver {
+    server: MockServer,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockWebdriver {
+    pub fn start(sign_in: SignIn) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            sign_in,
+            session: None,
+            sessions: 0,
+            url: "about:blank".to_string(),
+            page: Page::Blank,
+            navigation: None,
+            elements: Vec::new(),
+            typed: Vec::new(),
+            visited: Vec::new(),
+            cookies: Vec::new(),
+        }));
+
+        let service_state = state.clone();
+        let server = MockServer::start(move |parts, body| handle(service_state.clone(), parts, body));
+
+        Self {
+            server,
+            state,
+        }
+    }
+
+    /// URL to connect a `fantoccini::Client` to.
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// Every text typed so far, as `(<selector>, <text>)`.
+    pub fn typed(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().typed.clone()
+    }
+
+    /// Every URL loaded so far, including navigations from clicks.
+    pub fn visited(&self) -> Vec<String> {
+        self.state.lock().unwrap().visited.clone()
+    }
+
+    /// Returns true if a session was started and then closed.
+    pub fn is_closed(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.sessions > 0 && state.session.is_none()
+    }
+}
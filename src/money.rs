@@ -0,0 +1,164 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use rust_decimal::Decimal;
+use rusty_money::{iso, Money};
+use serde::{Deserialize, Deserializer, Serializer};
+
+/// A price in US dollars, the only currency Best Buy US uses.
+pub type Usd = Money<'static, iso::Currency>;
+
+pub fn usd(amount: Decimal) -> Usd {
+    Money::from_decimal(amount, iso::USD)
+}
+
+/// Parse a price as Best Buy formats it, e.g., `$1,299.99`, `-$5.00`,
+/// `499.99` or `FREE`.
+pub fn parse(price: &str) -> Result<Usd> {
+    let trimmed = price.trim();
+    if trimmed.eq_ignore_ascii_case("free") {
+        return Ok(usd(Decimal::new(0, 2)));
+    }
+
+    let (sign, amount) = match trimmed.strip_prefix('-') {
+        Some(amount) => ("-", amount),
+        None => ("", trimmed),
+    };
+    let amount = amount.trim_start_matches('$').replace(',', "");
+
+    let valid = !amount.is_empty() && amount.chars().all(|c| c.is_ascii_digit() || c == '.');
+    let decimal = match Decimal::from_str(&format!("{}{}", sign, amount)) {
+        Ok(decimal) if valid => decimal,
+        _ => anyhow::bail!("Invalid price: {}", price),
+    };
+
+    Ok(usd(decimal))
+}
+
+/// Convert a price given as a number, e.g., in the config file or by the
+/// pricing API. The shortest representation of the float is used, so
+/// `499.99` stays exactly $499.99.
+pub fn from_f64(price: f64) -> Result<Usd> {
+    if !price.is_finite() {
+        anyhow::bail!("Invalid price: {}", price);
+    }
+    parse(&price.to_string())
+}
+
+/// Returns true if `price` is more than `max_price` times `quantity`.
+pub fn exceeds(price: &Usd, max_price: &Usd, quantity: u32) -> bool {
+    *price.amount() > *max_price.amount() * Decimal::from(quantity)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPrice {
+    Number(f64),
+    Text(String),
+}
+
+impl RawPrice {
+    fn parse(self) -> Result<Usd> {
+        match self {
+            RawPrice::Number(price) => from_f64(price),
+            RawPrice::Text(price) => parse(&price),
+        }
+    }
+}
+
+/// Deserialize a price given as a number or a string.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Usd, D::Error> {
+    RawPrice::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserialize an optional price given as a number or a string.
+pub fn deserialize_option<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Usd>, D::Error> {
+    match Option::<RawPrice>::deserialize(deserializer)? {
+        Some(price) => price.parse().map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Deserialize an optional price, treating anything that isn't a price,
+/// e.g., "See price in cart", as missing.
+pub fn deserialize_lenient<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<Usd>, D::Error> {
+    let price = match Option::<RawPrice>::deserialize(deserializer) {
+        Ok(Some(price)) => price,
+        _ => return Ok(None),
+    };
+
+    match price.parse() {
+        Ok(price) => Ok(Some(price)),
+        Err(e) => {
+            log::debug!("{}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Serialize a price as a plain amount in dollars, e.g., `1299.99`, so
+/// consumers don't have to parse the display format.
+pub fn serialize<S: Serializer>(price: &Usd, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:.2}", price.amount()))
+}
+
+pub fn serialize_option<S: Serializer>(price: &Option<Usd>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match price {
+        Some(price) => serialize(price, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cents(cents: i64) -> Usd {
+        usd(Decimal::new(cents, 2))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("$1,299.99").unwrap(), cents(129999));
+        assert_eq!(parse("499.99").unwrap(), cents(49999));
+        assert_eq!(parse("-$5.00").unwrap(), cents(-500));
+        assert_eq!(parse("FREE").unwrap(), cents(0));
+        assert!(parse("See price in cart").is_err());
+        assert!(parse("$").is_err());
+        assert!(parse("1e3").is_err());
+
+        assert_eq!(from_f64(19.99).unwrap(), cents(1999));
+        assert_eq!(from_f64(500.0).unwrap(), cents(50000));
+        assert!(from_f64(f64::NAN).is_err());
+
+        assert_eq!(parse("$1,299.99").unwrap().to_string(), "$1,299.99");
+    }
+
+    #[test]
+    fn test_serialize() {
+        #[derive(serde::Serialize)]
+        struct Prices {
+            #[serde(serialize_with = "serialize")]
+            price: Usd,
+            #[serde(serialize_with = "serialize_option")]
+            shipping: Option<Usd>,
+            #[serde(serialize_with = "serialize_option")]
+            discount: Option<Usd>,
+        }
+
+        let prices = Prices { price: cents(129999), shipping: Some(from_f64(5.0).unwrap()), discount: None };
+        assert_eq!(
+            serde_json::to_value(&prices).unwrap(),
+            serde_json::json!({"price": "1299.99", "shipping": "5.00", "discount": null}),
+        );
+    }
+
+    #[test]
+    fn test_exceeds() {
+        assert!(exceeds(&cents(2000), &cents(1999), 1));
+        assert!(!exceeds(&cents(3998), &cents(1999), 2));
+        assert!(!exceeds(&cents(0), &cents(1999), 0));
+    }
+}
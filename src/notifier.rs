@@ -0,0 +1,77 @@
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::discord::DiscordWebhook;
+use crate::twilio::TwilioClient;
+
+struct TwilioNotifier {
+    client: TwilioClient,
+    from_number: String,
+    to_number: String,
+}
+
+/// Sends notifications to every configured channel.
+pub struct Notifier {
+    twilio: Option<TwilioNotifier>,
+    discord: Option<DiscordWebhook>,
+}
+
+impl Notifier {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let twilio = match TwilioClient::from_config(config)? {
+            Some(client) => {
+                let twilio_config = config.twilio.as_ref().unwrap();
+                Some(TwilioNotifier {
+                    client,
+                    from_number: twilio_config.from_number.clone(),
+                    to_number: twilio_config.to_number.clone(),
+                })
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            twilio,
+            discord: DiscordWebhook::from_config(config),
+        })
+    }
+
+    async fn send_sms(&self, message: &str) -> Result<()> {
+        if let Some(twilio) = &self.twilio {
+            twilio.client.send_message(
+                &twilio.from_number,
+                &twilio.to_number,
+                message
+            ).await?;
+
+            log::info!("Sent notification SMS successfully");
+        }
+
+        Ok(())
+    }
+
+    /// Send a message to all channels.
+    pub async fn send(&self, message: &str) -> Result<()> {
+        self.send_sms(message).await?;
+
+        if let Some(discord_webhook) = &self.discord {
+            discord_webhook.trigger(message).await?;
+            log::info!("Triggered Discord webhook successfully");
+        }
+
+        Ok(())
+    }
+
+    /// Send a message with a PNG image to all channels. The image is only
+    /// attached on Discord, since SMS only has text.
+    pub async fn send_with_image(&self, message: &str, png: &[u8]) -> Result<()> {
+        self.send_sms(message).await?;
+
+        if let Some(discord_webhook) = &self.discord {
+            discord_webhook.trigger_with_image(message, png).await?;
+            log::info!("Triggered Discord webhook successfully");
+        }
+
+        Ok(())
+    }
+}
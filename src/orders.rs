@@ -0,0 +1,380 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use regex::Regex;
+
+use crate::config::{Account, Config};
+use crate::email::Email;
+use crate::gmail::GmailClient;
+use crate::money::{self, Usd};
+
+static ORDER_QUERY: &str = "from:bestbuy.com";
+static ORDER_NUMBER_PAT: &str = r#"\b(BBY01-\d{9,12})\b"#;
+static CANCELLED_PAT: &str = r#"(?i)\b(?:has been|was) cancell?ed\b"#;
+/// Subject of the email sent when an order is placed, rather than later
+/// emails about the same order, e.g., when it ships
+static CONFIRMATION_PAT: &str = r#"(?i)^\s*(?:thanks for your order|thank you for your order|order confirmation)\b"#;
+static TOTAL_PAT: &str = r#"(?im)^\s*(?:order\s+)?total:?\s*\$([\d,]+\.\d{2})"#;
+static DELIVERY_PAT: &str = r#"(?im)^\s*(?:estimated delivery|arriving|get it by):?\s*(\S.*?)\s*$"#;
+static ITEM_SKU_PAT: &str = r#"(?i)^\s*sku:?\s*(\d+)\s*$"#;
+static ITEM_QUANTITY_PAT: &str = r#"(?i)^\s*qty:?\s*(\d+)\s*$"#;
+static ITEM_PRICE_PAT: &str = r#"(?i)^\s*price:?\s*\$([\d,]+\.\d{2})\s*$"#;
+
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(180);
+const CONFIRMATION_POLL_INTERVAL: Duration = Duration::from_secs(15);
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+/// Allowed clock skew between us and the email's timestamp
+const EMAIL_SKEW: i64 = 30;
+/// Only the newest few emails are checked on each poll
+const MAX_EMAILS: u32 = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct OrderItem {
+    pub name: String,
+    pub sku: String,
+    pub quantity: u32,
+    pub price: Option<Usd>,
+}
+
+/// The details of an order confirmation email.
+#[derive(Debug, PartialEq)]
+pub struct OrderConfirmation {
+    pub order_number: String,
+    pub items: Vec<OrderItem>,
+    pub total: Option<Usd>,
+    pub estimated_delivery: Option<String>,
+}
+
+impl fmt::Display for OrderConfirmation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Order {}", self.order_number)?;
+        if let Some(total) = &self.total {
+            write!(f, ", total {}", total)?;
+        }
+        if let Some(estimated_delivery) = &self.estimated_delivery {
+            write!(f, ", arriving {}", estimated_delivery)?;
+        }
+        for item in &self.items {
+            write!(f, "\n  {} x{} (SKU {})", item.name, item.quantity, item.sku)?;
+        }
+        Ok(())
+    }
+}
+
+/// An order email from Best Buy.
+#[derive(Debug, PartialEq)]
+pub enum OrderEmail {
+    Confirmation(OrderConfirmation),
+    Cancellation {
+        order_number: String,
+    },
+}
+
+impl OrderEmail {
+    /// Parse an order confirmation or cancellation email. Returns `None` for
+    /// any other email, such as a verification code or a shipping notice.
+    pub fn parse(email: &Email) -> Option<Self> {
+        let body = email.body_text();
+
+        let order_number_pat = Regex::new(ORDER_NUMBER_PAT).unwrap();
+        let order_number = order_number_pat
+            .captures(&email.subject)
+            .or_else(|| order_number_pat.captures(&body))
+            .map(|captures| captures[1].to_string())?;
+
+        let cancelled_pat = Regex::new(CANCELLED_PAT).unwrap();
+        if cancelled_pat.is_match(&email.subject) || cancelled_pat.is_match(&body) {
+            return Some(OrderEmail::Cancellation {
+                order_number,
+            });
+        }
+
+        if !Regex::new(CONFIRMATION_PAT).unwrap().is_match(&email.subject) {
+            return None;
+        }
+
+        let total = Regex::new(TOTAL_PAT)
+            .unwrap()
+            .captures(&body)
+            .and_then(|captures| money::parse(&captures[1]).ok());
+        let estimated_delivery = Regex::new(DELIVERY_PAT)
+            .unwrap()
+            .captures(&body)
+            .map(|captures| captures[1].to_string());
+
+        Some(OrderEmail::Confirmation(OrderConfirmation {
+            order_number,
+            items: parse_items(&body),
+            total,
+            estimated_delivery,
+        }))
+    }
+}
+
+/// Parse the items of an order, each listed as its name followed by `SKU:`,
+/// `Qty:` and `Price:` lines.
+fn parse_items(body: &str) -> Vec<OrderItem> {
+    let sku_pat = Regex::new(ITEM_SKU_PAT).unwrap();
+    let quantity_pat = Regex::new(ITEM_QUANTITY_PAT).unwrap();
+    let price_pat = Regex::new(ITEM_PRICE_PAT).unwrap();
+
+    let lines: Vec<&str> = body.lines().map(str::trim).filter(|line| !line.is_empty()).collect();
+
+    let mut items = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let sku = match sku_pat.captures(line) {
+            Some(captures) if i > 0 => captures[1].to_string(),
+            _ => continue,
+        };
+
+        let mut item = OrderItem {
+            name: lines[i - 1].to_string(),
+            sku,
+            quantity: 1,
+            price: None,
+        };
+
+        for detail in lines.iter().skip(i + 1).take(2) {
+            if let Some(captures) = quantity_pat.captures(detail) {
+                item.quantity = captures[1].parse().unwrap_or(1);
+            } else if let Some(captures) = price_pat.captures(detail) {
+                item.price = money::parse(&captures[1]).ok();
+            }
+        }
+
+        items.push(item);
+    }
+
+    items
+}
+
+/// A purchase waiting for its confirmation email.
+struct PendingConfirmation {
+    sku: String,
+    /// Only emails received after the order was placed are considered
+    since: i64,
+    deadline: Instant,
+}
+
+/// Watches an account's Gmail inbox for order confirmations and
+/// cancellations.
+pub struct OrderWatcher {
+    client: GmailClient,
+    user: String,
+    /// Only emails received after the bot started are considered
+    started_at: i64,
+    pending: Vec<PendingConfirmation>,
+    next_confirmation_check: Instant,
+    next_cancellation_check: Instant,
+    cancelled: HashSet<String>,
+}
+
+impl OrderWatcher {
+    /// Constructs an OrderWatcher if the account has a Gmail user.
+    pub async fn from_config(config: &Config, account: &Account) -> Result<Option<Self>> {
+        let user = match config.gmail_user(account) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let client = GmailClient::from_config(config, account).await?.unwrap();
+
+        Ok(Some(Self {
+            client,
+            user: user.to_string(),
+            started_at: unix_time(SystemTime::now())?,
+            pending: Vec::new(),
+            next_confirmation_check: Instant::now(),
+            next_cancellation_check: Instant::now(),
+            cancelled: HashSet::new(),
+        }))
+    }
+
+    /// Fetch the order emails received after `since`, newest first.
+    async fn order_emails(&self, since: i64) -> Result<Vec<OrderEmail>> {
+        let query = format!("{} after:{}", ORDER_QUERY, since);
+        let messages = self.client.list_messages(&self.user, &query, Some(MAX_EMAILS)).await?;
+
+        let mut orders = Vec::new();
+        for message_id in messages.iter().filter_map(|message| message.id.as_ref()) {
+            let raw = self.client.get_raw_message(&self.user, message_id).await?;
+            let email = Email::parse(&raw)?;
+            if email.date.map_or(false, |date| date >= since) {
+                orders.extend(OrderEmail::parse(&email));
+            }
+        }
+
+        Ok(orders)
+    }
+
+    /// Look for the confirmation of an order placed at `since` that
+    /// includes `sku` in later calls to `new_confirmations`.
+    pub fn expect_confirmation(&mut self, since: SystemTime, sku: &str) -> Result<()> {
+        self.pending.push(PendingConfirmation {
+            sku: sku.to_string(),
+            since: unix_time(since)? - EMAIL_SKEW,
+            deadline: Instant::now() + CONFIRMATION_TIMEOUT,
+        });
+        Ok(())
+    }
+
+    /// Returns true if a purchase is still waiting for its confirmation.
+    pub fn has_pending_confirmations(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    async fn find_confirmation(&self, pending: &PendingConfirmation) -> Result<Option<OrderConfirmation>> {
+        let confirmation = self.order_emails(pending.since).await?.into_iter().find_map(|order| match order {
+            OrderEmail::Confirmation(confirmation)
+                if confirmation.items.is_empty() || confirmation.items.iter().any(|item| item.sku == pending.sku) => {
+                Some(confirmation)
+            }
+            _ => None,
+        });
+
+        Ok(confirmation)
+    }
+
+    /// Returns the SKUs of the purchases confirmed since the last check,
+    /// with their confirmation, or with `None` once a purchase went a few
+    /// minutes without one. Checks at most every few seconds.
+    pub async fn new_confirmations(&mut self) -> Result<Vec<(String, Option<OrderConfirmation>)>> {
+        let now = Instant::now();
+        if self.pending.is_empty() || now < self.next_confirmation_check {
+            return Ok(Vec::new());
+        }
+        self.next_confirmation_check = now + CONFIRMATION_POLL_INTERVAL;
+
+        let mut found = Vec::new();
+        for pending in &self.pending {
+            found.push(self.find_confirmation(pending).await?);
+        }
+
+        let mut confirmations = Vec::new();
+        for (pending, confirmation) in std::mem::take(&mut self.pending).into_iter().zip(found) {
+            match confirmation {
+                Some(confirmation) => {
+                    log::info!("Found order confirmation: {}", confirmation.order_number);
+                    confirmations.push((pending.sku, Some(confirmation)));
+                }
+                None if now >= pending.deadline => {
+                    log::warn!("No order confirmation for {} after {:?}", pending.sku, CONFIRMATION_TIMEOUT);
+                    confirmations.push((pending.sku, None));
+                }
+                None => self.pending.push(pending),
+            }
+        }
+
+        Ok(confirmations)
+    }
+
+    /// Returns the order numbers cancelled since the last check. Checks at
+    /// most every few minutes.
+    pub async fn new_cancellations(&mut self) -> Result<Vec<String>> {
+        let now = Instant::now();
+        if now < self.next_cancellation_check {
+            return Ok(Vec::new());
+        }
+        self.next_cancellation_check = now + CANCELLATION_CHECK_INTERVAL;
+
+        let mut cancelled = Vec::new();
+        for order in self.order_emails(self.started_at - EMAIL_SKEW).await? {
+            if let OrderEmail::Cancellation { order_number } = order {
+                if self.cancelled.insert(order_number.clone()) {
+                    cancelled.push(order_number);
+                }
+            }
+        }
+
+        Ok(cancelled)
+    }
+}
+
+fn unix_time(time: SystemTime) -> Result<i64> {
+    Ok(time.duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn email(subject: &str, text: &str) -> Email {
+        Email {
+            subject: subject.to_string(),
+            date: None,
+            html: Vec::new(),
+            text: vec![text.to_string()],
+        }
+    }
+
+    #[test]
+    fn test_parse_confirmation() {
+        let confirmation = email("Thanks for your order, BBY01-806547239412", "
+Thanks for shopping with us!
+
+Order Number: BBY01-806547239412
+
+Sony - PlayStation 5 Console
+SKU: 6426149
+Qty: 1
+Price: $499.99
+
+Controller Charging Station
+SKU: 6430161
+Qty: 2
+Price: $29.99
+
+Subtotal: $559.97
+Estimated Tax: $46.20
+Total: $606.17
+
+Estimated Delivery: Thu, Jun 3
+");
+
+        let expected = OrderConfirmation {
+            order_number: "BBY01-806547239412".to_string(),
+            items: vec![
+                OrderItem {
+                    name: "Sony - PlayStation 5 Console".to_string(),
+                    sku: "6426149".to_string(),
+                    quantity: 1,
+                    price: money::parse("499.99").ok(),
+                },
+                OrderItem {
+                    name: "Controller Charging Station".to_string(),
+                    sku: "6430161".to_string(),
+                    quantity: 2,
+                    price: money::parse("29.99").ok(),
+                },
+            ],
+            total: money::parse("606.17").ok(),
+            estimated_delivery: Some("Thu, Jun 3".to_string()),
+        };
+
+        assert_eq!(OrderEmail::parse(&confirmation), Some(OrderEmail::Confirmation(expected)));
+    }
+
+    #[test]
+    fn test_parse_cancellation() {
+        let cancellation = email(
+            "Your order has been cancelled",
+            "We're sorry, your order BBY01-806547239412 has been cancelled.",
+        );
+
+        let expected = OrderEmail::Cancellation {
+            order_number: "BBY01-806547239412".to_string(),
+        };
+        assert_eq!(OrderEmail::parse(&cancellation), Some(expected));
+
+        let verification = email("Your Best Buy Verification Code", "Your verification code is 482913.");
+        assert_eq!(OrderEmail::parse(&verification), None);
+
+        // Later emails about an order aren't its confirmation
+        let shipped = email("Your order has shipped", "Order Number: BBY01-806547239412\nTotal: $606.17");
+        assert_eq!(OrderEmail::parse(&shipped), None);
+        let received = email("We received your order BBY01-806547239412", "We're processing your order.");
+        assert_eq!(OrderEmail::parse(&received), None);
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::bestbuy::{Cart, ItemInfo, SelectorMatch, StockStatus};
+use crate::challenge::ChallengeStatus;
+use crate::common::BotClientState;
+use crate::throttle::ThrottleStats;
+
+/// How command results and bot events are printed to stdout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+/// A single line of JSON output.
+///
+/// Every line has an `event` field naming the variant, followed by the
+/// variant's fields.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    StockCheck {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        account: Option<&'a str>,
+        #[serde(flatten)]
+        status: &'a StockStatus,
+    },
+    ItemInfo(&'a ItemInfo),
+    Cart(&'a Cart),
+    StateChange {
+        account: &'a str,
+        sku: &'a str,
+        state: BotClientState,
+    },
+    Notification {
+        account: &'a str,
+        message: &'a str,
+    },
+    SelectorCheck(&'a SelectorMatch),
+    Challenge {
+        account: &'a str,
+        url: &'a str,
+        status: ChallengeStatus,
+    },
+    Throttle {
+        account: &'a str,
+        #[serde(flatten)]
+        stats: &'a ThrottleStats,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+        }
+    }
+
+    /// Print a command result, either as text or as a JSON event.
+    pub fn print(&self, text: &dyn Display, event: &Event) {
+        match self.format {
+            OutputFormat::Text => println!("{}", text),
+            OutputFormat::Json => self.emit(event),
+        }
+    }
+
+    /// Print an event as a JSON line. Nothing is printed in text mode.
+    pub fn emit(&self, event: &Event) {
+        if self.format != OutputFormat::Json {
+            return;
+        }
+
+        match serde_json::to_string(event) {
+            Ok(line) => println!("{}", line),
+            Err(e) => log::error!("Failed to serialize event: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::{json, Value as Json};
+
+    use super::*;
+
+    fn to_json(event: &Event) -> Json {
+        serde_json::to_value(event).unwrap()
+    }
+
+    #[test]
+    fn test_event_json() {
+        let status: StockStatus = serde_json::from_value(json!({
+            "sku": "6426149",
+            "name": "Sony - PlayStation 5 Console",
+            "price": "$499.99",
+            "in_stock": true,
+        })).unwrap();
+        assert_eq!(to_json(&Event::StockCheck { account: Some("test"), status: &status }), json!({
+            "event": "stock_check",
+            "account": "test",
+            "sku": "6426149",
+            "name": "Sony - PlayStation 5 Console",
+            "price": "499.99",
+            "in_stock": true,
+        }));
+        assert_eq!(to_json(&Event::StockCheck { account: None, status: &status }).get("account"), None);
+
+        let item_info: ItemInfo = serde_json::from_value(json!({
+            "sku": "6426149",
+            "name": "Sony - PlayStation 5 Console",
+            "url": "https://www.bestbuy.com/site/6426149.p?skuId=6426149",
+            "price": {"regularPrice": 499.99, "currentPrice": 499.99, "customerPrice": 499.99},
+            "image_url": "https://pisces.bbystatic.com/6426149.jpg",
+            "description": "The PS5",
+        })).unwrap();
+        let json = to_json(&Event::ItemInfo(&item_info));
+        assert_eq!((&json["event"], &json["sku"]), (&json!("item_info"), &json!("6426149")));
+        assert_eq!(json["price"], json!({"regularPrice": "499.99", "currentPrice": "499.99", "customerPrice": "499.99"}));
+
+        let cart: Cart = serde_json::from_value(json!({
+            "id": "mock-cart",
+            "cartItemCount": "0",
+            "orderSummary": {"productTotal": "$0.00", "orderTotal": "$0.00"},
+        })).unwrap();
+        let json = to_json(&Event::Cart(&cart));
+        assert_eq!((&json["event"], &json["id"], &json["lineItems"]), (&json!("cart"), &json!("mock-cart"), &json!([])));
+        assert_eq!(json["orderSummary"]["orderTotal"], "0.00");
+
+        let event = Event::StateChange { account: "test", sku: "6426149", state: BotClientState::CartUpdated };
+        assert_eq!(to_json(&event), json!({
+            "event": "state_change",
+            "account": "test",
+            "sku": "6426149",
+            "state": "cart_updated",
+        }));
+
+        let event = Event::Notification { account: "test", message: "In Stock" };
+        assert_eq!(to_json(&event), json!({"event": "notification", "account": "test", "message": "In Stock"}));
+
+        let selector_match = SelectorMatch { field: "username", selector: "#fld-e".to_string(), matched: true };
+        assert_eq!(to_json(&Event::SelectorCheck(&selector_match)), json!({
+            "event": "selector_check",
+            "field": "username",
+            "selector": "#fld-e",
+            "matched": true,
+        }));
+
+        let event = Event::Challenge {
+            account: "test",
+            url: "https://www.bestbuy.com/identity/signin",
+            status: ChallengeStatus::TimedOut,
+        };
+        assert_eq!(to_json(&event), json!({
+            "event": "challenge",
+            "account": "test",
+            "url": "https://www.bestbuy.com/identity/signin",
+            "status": "timed_out",
+        }));
+
+        let stats = ThrottleStats {
+            requests: 10,
+            too_many_requests: 1,
+            server_errors: 2,
+            timeouts: 0,
+            retries: 3,
+            limited_secs: 4,
+            backoff_secs: 5,
+            requests_per_minute: 30.0,
+        };
+        assert_eq!(to_json(&Event::Throttle { account: "test", stats: &stats }), json!({
+            "event": "throttle",
+            "account": "test",
+            "requests": 10,
+            "too_many_requests": 1,
+            "server_errors": 2,
+            "timeouts": 0,
+            "retries": 3,
+            "limited_secs": 4,
+            "backoff_secs": 5,
+            "requests_per_minute": 30.0,
+        }));
+    }
+}
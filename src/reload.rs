@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::config::{Account, Config, Sku};
+
+/// Watches the config file for changes by polling its modification time.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = Self::modified(&path);
+        Self {
+            path,
+            modified,
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Reload the config if the file was modified since the last poll.
+    pub fn poll(&mut self) -> Option<Result<Config>> {
+        let modified = Self::modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return None;
+        }
+
+        self.modified = modified;
+
+        log::debug!("Config file {} changed, reloading", self.path.display());
+
+        Some(Config::load(&self.path))
+    }
+}
+
+/// Returns the settings that changed between `old` and `new` for the given
+/// account, but are only read at startup.
+pub fn restart_required(old: &Config, new: &Config, account: &Account) -> Vec<String> {
+    let mut changed = Vec::new();
+
+    if old.general.hostname != new.general.hostname {
+        changed.push("general.hostname".to_string());
+    }
+    if old.general.working_dir != new.general.working_dir {
+        changed.push("general.working_dir".to_string());
+    }
+    if old.webdriver != new.webdriver {
+        changed.push("webdriver".to_string());
+    }
+    if old.selectors != new.selectors {
+        changed.push("selectors".to_string());
+    }
+    if old.imap != new.imap {
+        changed.push("imap".to_string());
+    }
+    if old.proxy != new.proxy {
+        changed.push("proxy".to_string());
+    }
+    if old.rate_limit != new.rate_limit {
+        changed.push("rate_limit".to_string());
+    }
+    if old.api_checkout() != new.api_checkout() {
+        changed.push("bestbuy.api_checkout".to_string());
+    }
+
+    let new_account = find_account(new, account.name());
+    match new_account {
+        Some(new_account) => {
+            if old.gmail_user(account) != new.gmail_user(new_account)
+                || old.code_source(account) != new.code_source(new_account)
+                || old.sms_number(account) != new.sms_number(new_account) {
+                changed.push(format!("verification for account {}", account.name()));
+            }
+            if old.proxies(account) != new.proxies(new_account) {
+                changed.push(format!("proxies for account {}", account.name()));
+            }
+            if account.username != new_account.username || account.password != new_account.password {
+                changed.push(format!("credentials for account {}", account.name()));
+            }
+        }
+        None => changed.push(format!("removal of account {}", account.name())),
+    }
+
+    let old_count = old.bestbuy.as_ref().map_or(0, |bestbuy| bestbuy.accounts.len());
+    let new_count = new.bestbuy.as_ref().map_or(0, |bestbuy| bestbuy.accounts.len());
+    if old_count != new_count {
+        changed.push("bestbuy.accounts".to_string());
+    }
+
+    changed
+}
+
+/// Find an account by name.
+pub fn find_account<'a>(config: &'a Config, name: &str) -> Option<&'a Account> {
+    config.bestbuy.as_ref()?.accounts.iter().find(|account| account.name() == name)
+}
+
+/// The difference between two SKU lists.
+#[derive(Debug, Default)]
+pub struct SkuDiff {
+    pub added: Vec<Sku>,
+    pub removed: Vec<Sku>,
+    pub changed: Vec<Sku>,
+}
+
+impl SkuDiff {
+    pub fn new(old: &[Sku], new: &[Sku]) -> Self {
+        let find = |skus: &[Sku], sku: &Sku| skus.iter().find(|s| s.sku == sku.sku).cloned();
+
+        let mut diff = Self::default();
+
+        for sku in new {
+            match find(old, sku) {
+                None => diff.added.push(sku.clone()),
+                Some(old_sku) if old_sku != *sku => diff.changed.push(sku.clone()),
+                _ => (),
+            }
+        }
+
+        for sku in old {
+            if find(new, sku).is_none() {
+                diff.removed.push(sku.clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    pub fn log(&self) {
+        for sku in &self.added {
+            log::info!("Config reload: added SKU {}", sku.sku);
+        }
+        for sku in &self.removed {
+            log::info!("Config reload: removed SKU {}", sku.sku);
+        }
+        for sku in &self.changed {
+            log::info!("Config reload: updated SKU {}: {:?}", sku.sku, sku);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money;
+
+    #[test]
+    fn test_sku_diff() {
+        let old = vec![Sku::new("1".to_string()), Sku::new("2".to_string())];
+
+        let mut changed = Sku::new("2".to_string());
+        changed.max_price = money::from_f64(100.0).ok();
+        let new = vec![changed.clone(), Sku::new("3".to_string())];
+
+        let diff = SkuDiff::new(&old, &new);
+        assert_eq!(diff.added, vec![Sku::new("3".to_string())]);
+        assert_eq!(diff.removed, vec![Sku::new("1".to_string())]);
+        assert_eq!(diff.changed, vec![changed]);
+
+        assert!(SkuDiff::new(&old, &old).is_empty());
+    }
+}
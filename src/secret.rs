@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::{Captures, Regex};
+
+static ENV_VAR_PAT: &str = r#"\$\{([A-Za-z_][A-Za-z0-9_]*)\}"#;
+static KEYRING_PREFIX: &str = "keyring:";
+#[cfg(feature = "keyring")]
+static KEYRING_SERVICE: &str = "bestbot";
+
+/// Resolve the value of a secret config field.
+///
+/// If `file` is set, the secret is read from it (e.g., a Docker or Kubernetes
+/// secret mount). Otherwise, `${VAR}` references in `value` are replaced with
+/// the matching environment variables. A value of the form `keyring:<name>`
+/// is then looked up in the OS keyring under the `bestbot` service.
+pub fn resolve(field: &str, value: &str, file: Option<&Path>) -> Result<String> {
+    let resolved = match file {
+        Some(path) => {
+            if !value.is_empty() {
+                anyhow::bail!("Only one of `{}` and `{}_file` can be set", field, field);
+            }
+
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read `{}_file` from {}", field, path.display()))?;
+
+            // Secret files are usually written with a trailing newline
+            contents.trim_end_matches(&['\r', '\n'][..]).to_string()
+        }
+        None => interpolate_env(field, value)?,
+    };
+
+    let resolved = match resolved.strip_prefix(KEYRING_PREFIX) {
+        Some(name) => keyring_lookup(field, name)?,
+        None => resolved,
+    };
+
+    if resolved.is_empty() {
+        anyhow::bail!("`{}` is empty", field);
+    }
+
+    Ok(resolved)
+}
+
+/// Replace every `${VAR}` in `value` with the contents of the environment variable.
+fn interpolate_env(field: &str, value: &str) -> Result<String> {
+    let env_var_pat = Regex::new(ENV_VAR_PAT)?;
+    let mut missing: Option<String> = None;
+
+    let interpolated = env_var_pat
+        .replace_all(value, |caps: &Captures| {
+            std::env::var(&caps[1]).unwrap_or_else(|_| {
+                missing.get_or_insert_with(|| caps[1].to_string());
+                String::new()
+            })
+        })
+        .into_owned();
+
+    if let Some(var) = missing {
+        anyhow::bail!("`{}` references environment variable `{}`, which is not set", field, var);
+    }
+
+    Ok(interpolated)
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_lookup(field: &str, name: &str) -> Result<String> {
+    let keyring = keyring::Keyring::new(KEYRING_SERVICE, name);
+    keyring
+        .get_password()
+        .map_err(|e| anyhow::format_err!("Keyring lookup for `{}` failed: {}", field, e))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_lookup(field: &str, _name: &str) -> Result<String> {
+    anyhow::bail!("`{}` uses the keyring, but bestbot was built without the `keyring` feature", field)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_env() {
+        std::env::set_var("BESTBOT_TEST_SECRET", "hunter2");
+
+        let resolved = resolve("password", "${BESTBOT_TEST_SECRET}", None).unwrap();
+        assert_eq!(resolved, "hunter2");
+
+        let resolved = resolve("password", "pre-${BESTBOT_TEST_SECRET}-post", None).unwrap();
+        assert_eq!(resolved, "pre-hunter2-post");
+
+        assert!(resolve("password", "${BESTBOT_TEST_MISSING}", None).is_err());
+    }
+
+    #[test]
+    fn test_resolve_file() {
+        let path = std::env::temp_dir().join("bestbot-test-secret");
+        std::fs::write(&path, "hunter2\n").unwrap();
+
+        let resolved = resolve("password", "", Some(&path)).unwrap();
+        assert_eq!(resolved, "hunter2");
+
+        // Setting both the value and the file is ambiguous
+        assert!(resolve("password", "hunter2", Some(&path)).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_empty() {
+        assert!(resolve("password", "", None).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::Serialize;
+use tokio::time::sleep;
+
+use crate::config::RateLimit;
+
+const DEFAULT_REQUESTS_PER_MINUTE: f64 = 60.0;
+const DEFAULT_BURST: f64 = 5.0;
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_JITTER: f64 = 0.1;
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(120);
+/// The rate never drops below this fraction of the configured rate
+const MIN_RATE_FACTOR: f64 = 0.125;
+/// Fraction of the configured rate recovered after each successful request
+const RECOVERY_FACTOR: f64 = 0.05;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    /// Current rate in requests per second, lowered when throttled
+    rate: f64,
+    updated: Instant,
+}
+
+/// A token bucket that limits the request rate of an API client.
+///
+/// The rate is halved every time Best Buy throttles us, and slowly recovers
+/// to the configured rate as requests succeed.
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_rate: f64,
+    burst: f64,
+    max_retries: u32,
+    bucket: Mutex<Bucket>,
+    stats: ThrottleCounters,
+}
+
+impl RateLimiter {
+    pub fn new(rate_limit: &RateLimit) -> Self {
+        let max_rate = rate_limit.requests_per_minute.unwrap_or(DEFAULT_REQUESTS_PER_MINUTE) / 60.0;
+        let burst = rate_limit.burst.map_or(DEFAULT_BURST, f64::from).max(1.0);
+
+        Self {
+            max_rate,
+            burst,
+            max_retries: rate_limit.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                rate: max_rate,
+                updated: Instant::now(),
+            }),
+            stats: ThrottleCounters::default(),
+        }
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    /// Take a token, and return how long to wait before sending the
+    /// request.
+    fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(self.burst);
+        bucket.updated = now;
+
+        // Tokens can go negative, which queues up concurrent requests
+        bucket.tokens -= 1.0;
+        if bucket.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        }
+    }
+
+    /// Wait until a request can be sent.
+    pub async fn acquire(&self) {
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+
+        let wait = self.reserve();
+        if wait > Duration::from_secs(0) {
+            log::trace!("Rate limited, waiting {:?}", wait);
+            self.stats.limited_ms.fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+            sleep(wait).await;
+        }
+    }
+
+    /// Slow down after a 429 or a server error.
+    fn slow_down(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate / 2.0).max(self.max_rate * MIN_RATE_FACTOR);
+        log::debug!("Lowered the request rate to {:.1}/min", bucket.rate * 60.0);
+    }
+
+    /// Speed back up towards the configured rate after a success.
+    pub fn on_success(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate + self.max_rate * RECOVERY_FACTOR).min(self.max_rate);
+    }
+
+    /// Record a failed request that will be retried, and return how long to
+    /// back off. `retry_after` is the server's `Retry-After`, if any.
+    pub fn on_retry(&self, reason: RetryReason, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let counter = match reason {
+            RetryReason::TooManyRequests => &self.stats.too_many_requests,
+            RetryReason::ServerError => &self.stats.server_errors,
+            RetryReason::Timeout => &self.stats.timeouts,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.stats.retries.fetch_add(1, Ordering::Relaxed);
+
+        if reason != RetryReason::Timeout {
+            self.slow_down();
+        }
+
+        let delay = backoff(attempt).max(retry_after.unwrap_or_default());
+        self.stats.backoff_ms.fetch_add(delay.as_millis() as u64, Ordering::Relaxed);
+
+        delay
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        let rate = self.bucket.lock().unwrap().rate;
+        self.stats.snapshot(rate * 60.0)
+    }
+}
+
+/// Why a request is retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryReason {
+    TooManyRequests,
+    ServerError,
+    Timeout,
+}
+
+impl fmt::Display for RetryReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryReason::TooManyRequests => write!(f, "too many requests"),
+            RetryReason::ServerError => write!(f, "server error"),
+            RetryReason::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+/// Exponential backoff with full jitter: a random delay of up to
+/// `BACKOFF_BASE * 2^attempt`, capped at `BACKOFF_MAX`.
+pub fn backoff(attempt: u32) -> Duration {
+    let max = BACKOFF_BASE
+        .checked_mul(2u32.saturating_pow(attempt))
+        .map_or(BACKOFF_MAX, |max| max.min(BACKOFF_MAX));
+
+    Duration::from_secs_f64(rand::thread_rng().gen_range(0.0..=max.as_secs_f64()))
+}
+
+/// Randomize a polling interval by up to `rate_limit.jitter` (a fraction)
+/// in either direction, so checks don't happen on a fixed schedule.
+pub fn jitter(interval: Duration, rate_limit: &RateLimit) -> Duration {
+    let jitter = rate_limit.jitter.unwrap_or(DEFAULT_JITTER).clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return interval;
+    }
+
+    let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+    interval.mul_f64(factor)
+}
+
+#[derive(Debug, Default)]
+struct ThrottleCounters {
+    requests: AtomicU64,
+    too_many_requests: AtomicU64,
+    server_errors: AtomicU64,
+    timeouts: AtomicU64,
+    retries: AtomicU64,
+    limited_ms: AtomicU64,
+    backoff_ms: AtomicU64,
+}
+
+impl ThrottleCounters {
+    fn snapshot(&self, requests_per_minute: f64) -> ThrottleStats {
+        ThrottleStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            too_many_requests: self.too_many_requests.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            limited_secs: self.limited_ms.load(Ordering::Relaxed) / 1000,
+            backoff_secs: self.backoff_ms.load(Ordering::Relaxed) / 1000,
+            requests_per_minute,
+        }
+    }
+}
+
+/// How often the API client was throttled since it was created.
+#[derive(Clone, Debug, Serialize)]
+pub struct ThrottleStats {
+    pub requests: u64,
+    pub too_many_requests: u64,
+    pub server_errors: u64,
+    pub timeouts: u64,
+    pub retries: u64,
+    /// Seconds spent waiting on the rate limiter
+    pub limited_secs: u64,
+    /// Seconds spent backing off before retries
+    pub backoff_secs: u64,
+    /// The current, possibly lowered, request rate
+    pub requests_per_minute: f64,
+}
+
+impl fmt::Display for ThrottleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} throttled (429), {} server errors, {} timeouts, {} retries, \
+             {}s rate limited, {}s backing off, now {:.1} requests/min",
+            self.requests,
+            self.too_many_requests,
+            self.server_errors,
+            self.timeouts,
+            self.retries,
+            self.limited_secs,
+            self.backoff_secs,
+            self.requests_per_minute,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let rate_limit = RateLimit {
+            requests_per_minute: Some(60.0),
+            burst: Some(2),
+            ..RateLimit::default()
+        };
+        let limiter = RateLimiter::new(&rate_limit);
+
+        // The burst goes out right away, then requests are spaced out
+        assert_eq!(limiter.reserve(), Duration::from_secs(0));
+        assert_eq!(limiter.reserve(), Duration::from_secs(0));
+        let wait = limiter.reserve();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+
+        // Throttling halves the rate, and successes slowly restore it
+        let delay = limiter.on_retry(RetryReason::TooManyRequests, 0, Some(Duration::from_secs(5)));
+        assert_eq!(delay, Duration::from_secs(5));
+        assert_eq!(limiter.stats().requests_per_minute, 30.0);
+        limiter.on_success();
+        assert!((limiter.stats().requests_per_minute - 33.0).abs() < 1e-9);
+
+        let stats = limiter.stats();
+        assert_eq!((stats.too_many_requests, stats.retries), (1, 1));
+    }
+
+    #[test]
+    fn test_backoff_and_jitter() {
+        for attempt in 0..10 {
+            let max = Duration::from_secs(2u64.pow(attempt)).min(BACKOFF_MAX);
+            assert!(backoff(attempt) <= max);
+        }
+        assert!(backoff(u32::MAX) <= BACKOFF_MAX);
+
+        let rate_limit = RateLimit {
+            jitter: Some(0.2),
+            ..RateLimit::default()
+        };
+        for _ in 0..100 {
+            let interval = jitter(Duration::from_secs(10), &rate_limit);
+            assert!(interval >= Duration::from_secs(8) && interval <= Duration::from_secs(12));
+        }
+    }
+}
@@ -1,7 +1,19 @@
 use anyhow::Result;
+use serde::Deserialize;
 
 use crate::config::Config;
 
+/// A single SMS, as returned by the Messages list API.
+#[derive(Debug, Deserialize)]
+pub struct TwilioMessage {
+    pub body: String,
+    pub from: String,
+    /// "inbound" for received messages
+    pub direction: String,
+    /// RFC 2822 date (e.g., "Tue, 25 May 2021 18:03:10 +0000")
+    pub date_sent: Option<String>,
+}
+
 pub struct TwilioClient {
     sid: String,
     auth_token: String,
@@ -45,6 +57,28 @@ impl TwilioClient {
 
         Ok(())
     }
+
+    /// List the latest `limit` messages sent to the given number, newest first.
+    pub async fn list_messages(&self, to: &str, limit: u32) -> Result<Vec<TwilioMessage>> {
+        let endpoint = format!("{}/{}/Messages.json", Self::BASE_URL, self.sid);
+
+        #[derive(Deserialize)]
+        struct MessageList {
+            messages: Vec<TwilioMessage>,
+        }
+
+        let resp: MessageList = self.client
+            .get(&endpoint)
+            .query(&[("To", to), ("PageSize", &limit.to_string())])
+            .basic_auth(&self.sid, Some(&self.auth_token))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp.messages)
+    }
 }
 
 #[cfg(test)]
@@ -63,4 +97,16 @@ mod test {
 
         client.send_message(&from_number, &to_number, "Test passed!").await.unwrap();
     }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_twilio_list_messages() {
+        let sid = std::env::var("TWILIO_SID").unwrap();
+        let auth_token = std::env::var("TWILIO_AUTH_TOKEN").unwrap();
+        let from_number = std::env::var("TWILIO_FROM_NUMBER").unwrap();
+
+        let client = TwilioClient::new(sid, auth_token).unwrap();
+
+        client.list_messages(&from_number, 5).await.unwrap();
+    }
 }
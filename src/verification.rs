@@ -0,0 +1,313 @@
+use std::cmp::Reverse;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::time::sleep;
+
+use crate::config::{Account, CodeSource, Config, Imap};
+use crate::email::Email;
+use crate::gmail::GmailClient;
+use crate::twilio::{TwilioClient, TwilioMessage};
+
+static GMAIL_CODE_QUERY: &str = r#"from:bestbuy.com subject:"verification code""#;
+static IMAP_CODE_QUERY: &str = r#"FROM "bestbuy.com" SUBJECT "verification code""#;
+static SMS_CODE_PAT: &str = r#"\b(\d{6})\b"#;
+
+const CODE_TIMEOUT: Duration = Duration::from_secs(120);
+const CODE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Allowed clock skew between us and the code's timestamp
+const CODE_SKEW: i64 = 30;
+/// Only the newest few matching emails are checked on each poll
+const MAX_EMAILS: usize = 5;
+/// Only the newest few SMS messages are checked on each poll
+const MAX_SMS_MESSAGES: u32 = 10;
+
+/// A source of sign-in verification codes.
+#[async_trait(?Send)]
+pub trait CodeProvider {
+    /// Check once for a code sent after `since` (seconds since the epoch).
+    async fn find_code(&self, since: i64) -> Result<Option<String>>;
+}
+
+/// Build the code provider configured for an account, if any.
+pub async fn from_config(config: &Config, account: &Account) -> Result<Option<Box<dyn CodeProvider>>> {
+    let provider: Box<dyn CodeProvider> = match config.code_source(account) {
+        Some(CodeSource::Gmail) => {
+            let user = config
+                .gmail_user(account)
+                .ok_or_else(|| anyhow::format_err!("Gmail verification requires a `gmail_user`"))?;
+            let client = GmailClient::from_config(config, account).await?.unwrap();
+
+            Box::new(GmailCodeProvider {
+                client,
+                user: user.to_string(),
+            })
+        }
+        Some(CodeSource::Imap) => {
+            let imap = config.imap
+                .clone()
+                .ok_or_else(|| anyhow::format_err!("IMAP verification requires an `[imap]` section"))?;
+
+            Box::new(ImapCodeProvider {
+                config: imap,
+            })
+        }
+        Some(CodeSource::Sms) => {
+            let client = TwilioClient::from_config(config)?
+                .ok_or_else(|| anyhow::format_err!("SMS verification requires a `[twilio]` section"))?;
+            let number = config.sms_number(account).unwrap();
+
+            Box::new(SmsCodeProvider {
+                client,
+                number: number.to_string(),
+            })
+        }
+        None => return Ok(None),
+    };
+
+    Ok(Some(provider))
+}
+
+/// Poll `provider` until a code sent after `since` arrives.
+pub async fn wait_for_code(provider: &dyn CodeProvider, since: SystemTime) -> Result<String> {
+    let since = since.duration_since(UNIX_EPOCH)?.as_secs() as i64 - CODE_SKEW;
+    let deadline = Instant::now() + CODE_TIMEOUT;
+
+    loop {
+        if let Some(code) = provider.find_code(since).await? {
+            log::info!("Verification code: {}", code);
+            return Ok(code);
+        }
+
+        if Instant::now() >= deadline {
+            anyhow::bail!("Timed out after {:?} waiting for a verification code", CODE_TIMEOUT);
+        }
+
+        log::debug!("No verification code yet, checking again in {:?}", CODE_POLL_INTERVAL);
+        sleep(CODE_POLL_INTERVAL).await;
+    }
+}
+
+/// Returns the code in the newest email sent after `since`.
+fn newest_code(mut emails: Vec<Email>, since: i64) -> Option<String> {
+    emails.retain(|email| email.date.map_or(false, |date| date >= since));
+    emails.sort_by_key(|email| Reverse(email.date));
+    emails.iter().find_map(|email| email.verification_code())
+}
+
+/// Reads codes from Gmail using the Gmail API.
+pub struct GmailCodeProvider {
+    client: GmailClient,
+    user: String,
+}
+
+#[async_trait(?Send)]
+impl CodeProvider for GmailCodeProvider {
+    async fn find_code(&self, since: i64) -> Result<Option<String>> {
+        let query = format!("{} after:{}", GMAIL_CODE_QUERY, since);
+        let messages = self.client.list_messages(&self.user, &query, Some(MAX_EMAILS as u32)).await?;
+
+        let mut emails = Vec::new();
+        for message_id in messages.iter().filter_map(|message| message.id.as_ref()) {
+            let raw = self.client.get_raw_message(&self.user, message_id).await?;
+            emails.push(Email::parse(&raw)?);
+        }
+
+        Ok(newest_code(emails, since))
+    }
+}
+
+/// Reads codes from any mailbox over IMAP.
+pub struct ImapCodeProvider {
+    config: Imap,
+}
+
+impl ImapCodeProvider {
+    fn fetch_emails(config: &Imap, since: i64) -> Result<Vec<Email>> {
+        let host = config.host.as_str();
+        let tls = config.tls.unwrap_or(true);
+        let port = config.port.unwrap_or(if tls { 993 } else { 143 });
+
+        if tls {
+            let connector = native_tls::TlsConnector::builder().build()?;
+            let client = imap::connect((host, port), host, &connector).map_err(imap_error)?;
+            let session = client
+                .login(&config.username, &config.password)
+                .map_err(|(e, _)| imap_error(e))?;
+            Self::search(session, config, since)
+        } else {
+            // Only meant for local testing
+            let stream = TcpStream::connect((host, port))?;
+            let mut client = imap::Client::new(stream);
+            client.read_greeting().map_err(imap_error)?;
+            let session = client
+                .login(&config.username, &config.password)
+                .map_err(|(e, _)| imap_error(e))?;
+            Self::search(session, config, since)
+        }
+    }
+
+    fn search<T: Read + Write>(mut session: imap::Session<T>, config: &Imap, since: i64) -> Result<Vec<Email>> {
+        let mailbox = config.mailbox.as_deref().unwrap_or("INBOX");
+        session.select(mailbox).map_err(imap_error)?;
+
+        // `SINCE` only has day granularity, and is interpreted in the server's
+        // timezone, so search from the day before and filter by date afterwards
+        let query = format!("{} SINCE {}", IMAP_CODE_QUERY, imap_date(since - 86400));
+        let mut seqs: Vec<u32> = session.search(query).map_err(imap_error)?.into_iter().collect();
+        seqs.sort_unstable();
+
+        let newest: Vec<String> = seqs.iter().rev().take(MAX_EMAILS).map(|seq| seq.to_string()).collect();
+
+        let mut emails = Vec::new();
+        if !newest.is_empty() {
+            let fetches = session.fetch(newest.join(","), "RFC822").map_err(imap_error)?;
+            for body in fetches.iter().filter_map(|fetch| fetch.body()) {
+                emails.push(Email::parse(body)?);
+            }
+        }
+
+        session.logout().map_err(imap_error)?;
+
+        Ok(emails)
+    }
+}
+
+#[async_trait(?Send)]
+impl CodeProvider for ImapCodeProvider {
+    async fn find_code(&self, since: i64) -> Result<Option<String>> {
+        // The IMAP client is blocking
+        let config = self.config.clone();
+        let emails = tokio::task::spawn_blocking(move || Self::fetch_emails(&config, since)).await??;
+
+        Ok(newest_code(emails, since))
+    }
+}
+
+/// Reads codes sent by SMS to a Twilio number.
+pub struct SmsCodeProvider {
+    client: TwilioClient,
+    number: String,
+}
+
+#[async_trait(?Send)]
+impl CodeProvider for SmsCodeProvider {
+    async fn find_code(&self, since: i64) -> Result<Option<String>> {
+        let messages = self.client.list_messages(&self.number, MAX_SMS_MESSAGES).await?;
+        Ok(newest_sms_code(&messages, since))
+    }
+}
+
+/// Returns the code in the newest inbound SMS sent after `since`.
+fn newest_sms_code(messages: &[TwilioMessage], since: i64) -> Option<String> {
+    let code_pat = regex::Regex::new(SMS_CODE_PAT).unwrap();
+
+    // Twilio lists messages newest first
+    messages
+        .iter()
+        .filter(|message| message.direction == "inbound")
+        .filter(|message| {
+            message.date_sent
+                .as_deref()
+                .and_then(|date| mailparse::dateparse(date).ok())
+                .map_or(false, |date| date >= since)
+        })
+        .find_map(|message| code_pat.captures(&message.body).map(|captures| captures[1].to_string()))
+}
+
+fn imap_error(e: imap::error::Error) -> anyhow::Error {
+    anyhow::format_err!("IMAP error: {}", e)
+}
+
+/// Format a timestamp as an IMAP date (e.g., `25-May-2021`).
+fn imap_date(timestamp: i64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // Convert days since the epoch to a civil date:
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{}-{}-{}", day, MONTHS[(month - 1) as usize], year)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn email(date: i64, code: &str) -> Email {
+        Email {
+            subject: String::new(),
+            date: Some(date),
+            html: Vec::new(),
+            text: vec![format!("Your verification code is {}", code)],
+        }
+    }
+
+    #[test]
+    fn test_newest_code() {
+        let emails = vec![email(100, "111111"), email(300, "333333"), email(200, "222222")];
+        assert_eq!(newest_code(emails, 150), Some("333333".to_string()));
+
+        let emails = vec![email(100, "111111")];
+        assert_eq!(newest_code(emails, 150), None);
+    }
+
+    #[test]
+    fn test_newest_sms_code() {
+        let sms = |body: &str, direction: &str, date_sent: &str| TwilioMessage {
+            body: body.to_string(),
+            from: "+15555555555".to_string(),
+            direction: direction.to_string(),
+            date_sent: Some(date_sent.to_string()),
+        };
+
+        let messages = vec![
+            sms("In Stock: PS5 for $499.99", "outbound-api", "Tue, 25 May 2021 18:05:00 +0000"),
+            sms("Best Buy: Your verification code is 482913.", "inbound", "Tue, 25 May 2021 18:03:10 +0000"),
+            sms("Best Buy: Your verification code is 111111.", "inbound", "Mon, 24 May 2021 10:00:00 +0000"),
+        ];
+
+        assert_eq!(newest_sms_code(&messages, 1621965700), Some("482913".to_string()));
+        assert_eq!(newest_sms_code(&messages, 1621966000), None);
+    }
+
+    #[test]
+    fn test_imap_date() {
+        assert_eq!(imap_date(0), "1-Jan-1970");
+        assert_eq!(imap_date(1621965790), "25-May-2021");
+        assert_eq!(imap_date(1709164800), "29-Feb-2024");
+    }
+
+    #[ignore]
+    #[tokio::test]
+    async fn test_imap_code_provider() {
+        let config = Imap {
+            host: std::env::var("IMAP_HOST").unwrap(),
+            port: std::env::var("IMAP_PORT").ok().map(|port| port.parse().unwrap()),
+            username: std::env::var("IMAP_USERNAME").unwrap(),
+            password: std::env::var("IMAP_PASSWORD").unwrap(),
+            password_file: None,
+            tls: std::env::var("IMAP_TLS").ok().map(|tls| tls == "true"),
+            mailbox: None,
+        };
+
+        let provider = ImapCodeProvider { config };
+        let code = provider.find_code(0).await.unwrap();
+
+        assert!(code.is_some());
+    }
+}